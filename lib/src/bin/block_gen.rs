@@ -3,7 +3,7 @@ use chrono::Utc;
 use std::env;
 use std::process::exit;
 
-use btclib::types::{Block, BlockHeader, Transactions, TransactionsOutput};
+use btclib::types::{Block, BlockHeader, OutputLock, Transactions, TransactionsOutput};
 use btclib::util::{Saveable, MerkleRoot};
 use btclib::sha256::Hash;
 use btclib::crypto::PrivateKey;
@@ -22,7 +22,7 @@ fn main() {
         vec![TransactionsOutput {
             unique_id: Uuid::new_v4(),
             value: btclib::INITIAL_REWARD * 10u64.pow(8),
-            pubkey: priv_key.public_key(),
+            lock: OutputLock::PubKeyHash(priv_key.public_key().pubkey_hash()),
         }],
     )];
     let merkle_root = MerkleRoot::calculate(&txs);
@@ -1,4 +1,3 @@
-use std::fs::File;
 use std::env;
 use std::process::exit;
 
@@ -13,9 +12,7 @@ fn main() {
         exit(1);
     };
 
-    if let Ok(file) = File::open(path) {
-        let block = Block::load(file)
-                            .expect("Failed to load block");
-        println!("{:?}", block);                         
-    }
+    let block = Block::load_from_file(path)
+                        .expect("Failed to load block");
+    println!("{:?}", block);                         
 }
\ No newline at end of file
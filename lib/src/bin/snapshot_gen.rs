@@ -0,0 +1,25 @@
+use std::env;
+use std::process::exit;
+
+use btclib::types::BlockChain;
+use btclib::util::Saveable;
+
+fn main() {
+    let (dir, height, path) = if let (Some(arg1), Some(arg2), Some(arg3)) =
+        (env::args().nth(1), env::args().nth(2), env::args().nth(3)) {
+        (arg1, arg2, arg3)
+    } else {
+        eprintln!("Usage: snapshot_gen <data_dir> <height> <snapshot_file>");
+        exit(1);
+    };
+    let height: u64 = height.parse().unwrap_or_else(|_| {
+        eprintln!("<height> should be a block height");
+        exit(1);
+    });
+
+    let blockchain = BlockChain::open(dir).expect("Failed to open blockchain");
+    let snapshot = blockchain.utxo_snapshot(height).expect("Failed to create snapshot");
+    snapshot.save_to_file(path).expect("Failed to save snapshot");
+    println!("Snapshot at height {} with {} utxos", snapshot.height, snapshot.utxos.len());
+    println!("Commitment: {}", snapshot.commitment());
+}
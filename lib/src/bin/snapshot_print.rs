@@ -0,0 +1,21 @@
+use std::env;
+use std::process::exit;
+
+use btclib::types::UtxoSnapshot;
+use btclib::util::Saveable;
+
+fn main() {
+    let path = if let Some(arg) = env::args().nth(1) {
+        arg
+    } else {
+        eprintln!("Usage: snapshot_print <snapshot_file>");
+        exit(1);
+    };
+
+    let snapshot = UtxoSnapshot::load_from_file(path)
+                        .expect("Failed to load snapshot");
+    println!("Height:     {}", snapshot.height);
+    println!("Block:      {}", snapshot.block_hash);
+    println!("Utxos:      {}", snapshot.utxos.len());
+    println!("Commitment: {}", snapshot.commitment());
+}
@@ -2,7 +2,7 @@ use uuid::Uuid;
 use std::env;
 use std::process::exit;
 
-use btclib::types::{Transactions, TransactionsOutput};
+use btclib::types::{OutputLock, Transactions, TransactionsOutput};
 use btclib::util::Saveable;
 use btclib::crypto::PrivateKey;
 
@@ -20,7 +20,7 @@ fn main() {
         vec![TransactionsOutput {
             unique_id: Uuid::new_v4(),
             value: btclib::INITIAL_REWARD * 10u64.pow(8),
-            pubkey: priv_key.public_key(),
+            lock: OutputLock::PubKeyHash(priv_key.public_key().pubkey_hash()),
         }],
     );
     txs.save_to_file(path).expect("Failed to save transaction");
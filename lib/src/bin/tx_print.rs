@@ -1,7 +1,7 @@
-use std::fs::File;
 use std::env;
 use std::process::exit;
 
+use btclib::network::Network;
 use btclib::types::Transactions;
 use btclib::util::Saveable;
 
@@ -13,9 +13,15 @@ fn main() {
         exit(1);
     };
 
-    if let Ok(file) = File::open(path) {
-        let tx = Transactions::load(file)
-                            .expect("Failed to load transaction");
-        println!("{:?}", tx);                         
+    let tx = Transactions::load_from_file(path)
+                        .expect("Failed to load transaction");
+    println!("Transaction: {}", tx.hash());
+    for input in &tx.inputs {
+        println!("  input:  {}", input.prev_transaction_output_hash);
+    }
+    for output in &tx.outputs {
+        println!("  output: {} -> {}",
+                output.value,
+                output.lock.address(Network::default()));
     }
 }
\ No newline at end of file
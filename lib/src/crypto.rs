@@ -4,12 +4,60 @@ use ecdsa::{
     SigningKey,
     VerifyingKey
 };
-use k256::Secp256k1;
+use k256::elliptic_curve::{
+    group::Group,
+    ops::{LinearCombinationExt, Reduce},
+    point::DecompactPoint,
+    Field,
+    PrimeField,
+};
+use k256::schnorr::{
+    Signature as SchnorrSignature,
+    SigningKey as SchnorrSigningKey,
+    VerifyingKey as SchnorrVerifyingKey,
+};
+use k256::pkcs8::{
+    DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding,
+};
+use k256::{AffinePoint, FieldBytes, ProjectivePoint, Scalar, Secp256k1};
+use rand::RngCore;
+use ripemd::Ripemd160;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read,
+    Result as IOResult, Write};
+use std::str::FromStr;
+use zeroize::{ZeroizeOnDrop, Zeroizing};
 use crate::sha256::Hash;
+use crate::error::BtcError;
+use crate::network::Network;
+use crate::util::Saveable;
+
+//64 byte compact r || s encoding of an ECDSA or Schnorr signature
+pub const SIGNATURE_SIZE: usize = 64;
+//33 byte compressed SEC1 encoding of a public key
+pub const PUBLIC_KEY_SIZE: usize = 33;
+//32 byte BIP340 x-only encoding of a public key
+pub const XONLY_PUBLIC_KEY_SIZE: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureKind {
+    Ecdsa,
+    Schnorr,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Signature(pub ECDSASignature<Secp256k1>);
+pub enum Signature {
+    Ecdsa(
+        #[serde(with = "signature_serde")]
+        ECDSASignature<Secp256k1>
+    ),
+    Schnorr(
+        #[serde(with = "schnorr_serde")]
+        SchnorrSignature
+    ),
+}
 impl Signature {
     // sign a crate::types::TransactionOutput from its Sha256 hash
     pub fn sign_output(
@@ -17,21 +65,307 @@ impl Signature {
         private_key: &PrivateKey,
     ) -> Self {
         let signing_key = &private_key.0;
-        let signature = signing_key.sign(&output_hash.as_bytes());
-        return Signature(signature);
+        let signature: ECDSASignature<Secp256k1> = signing_key.sign(&output_hash.as_bytes());
+        //signer already produces low-S, normalize defensively
+        return Signature::Ecdsa(signature.normalize_s().unwrap_or(signature));
+    }
+
+    // BIP340 sign the raw 32 byte output hash
+    pub fn sign_output_schnorr(
+        output_hash: &Hash,
+        private_key: &PrivateKey,
+    ) -> Self {
+        let signing_key = SchnorrSigningKey::from(*private_key.0.as_nonzero_scalar());
+        let mut aux_rand = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut aux_rand);
+        let signature = signing_key.sign_raw(&output_hash.as_bytes(), &aux_rand)
+                            .expect("BUG: Schnorr signing failed");
+        return Signature::Schnorr(signature);
     }
 
-    //verify signature
+    //verify signature, rejecting malleable high-S ECDSA signatures
     pub fn verify(self: &Self, output_hash: &Hash,
                     public_key: &PublicKey) -> bool {
-                        return public_key.0.verify(&output_hash.as_bytes(), &self.0).is_ok();
+                        return match self {
+                            Signature::Ecdsa(signature) => {
+                                if signature.normalize_s().is_some() {
+                                    return false;
+                                }
+                                public_key.0.verify(&output_hash.as_bytes(), signature).is_ok()
+                            }
+                            Signature::Schnorr(signature) => {
+                                public_key.x_only().0
+                                    .verify_raw(&output_hash.as_bytes(), signature)
+                                    .is_ok()
+                            }
+                        };
                     }
+
+    pub fn kind(self: &Self) -> SignatureKind {
+        return match self {
+            Signature::Ecdsa(_) => SignatureKind::Ecdsa,
+            Signature::Schnorr(_) => SignatureKind::Schnorr,
+        };
+    }
+
+    pub fn to_bytes(self: &Self) -> [u8; SIGNATURE_SIZE] {
+        return match self {
+            Signature::Ecdsa(signature) => signature.to_bytes().into(),
+            Signature::Schnorr(signature) => signature.to_bytes(),
+        };
+    }
+
+    pub fn from_bytes(kind: SignatureKind, bytes: &[u8]) -> Result<Self, BtcError> {
+        if bytes.len() != SIGNATURE_SIZE {
+            return Err(BtcError::InvalidSignature);
+        }
+        return match kind {
+            SignatureKind::Ecdsa => ECDSASignature::from_slice(bytes)
+                                        .map(Signature::Ecdsa)
+                                        .map_err(|_| BtcError::InvalidSignature),
+            SignatureKind::Schnorr => SchnorrSignature::try_from(bytes)
+                                        .map(Signature::Schnorr)
+                                        .map_err(|_| BtcError::InvalidSignature),
+        };
+    }
+
+    pub fn to_hex(self: &Self) -> String {
+        return hex::encode(self.to_bytes());
+    }
+
+    pub fn from_hex(kind: SignatureKind, s: &str) -> Result<Self, BtcError> {
+        let bytes = hex::decode(s).map_err(|_| BtcError::InvalidSignature)?;
+        return Self::from_bytes(kind, &bytes);
+    }
+}
+
+// BIP340 batch verification of Schnorr signatures over
+// output hashes. Checks all signatures with one multi scalar
+// multiplication instead of one per signature
+#[derive(Default)]
+pub struct SchnorrBatch {
+    entries: Vec<(Hash, SchnorrSignature, XOnlyPublicKey)>,
+}
+
+impl SchnorrBatch {
+
+    pub fn new() -> Self {
+        return SchnorrBatch { entries: Vec::new() };
+    }
+
+    pub fn push(self: &mut Self, output_hash: Hash,
+                signature: SchnorrSignature, public_key: XOnlyPublicKey) {
+        self.entries.push((output_hash, signature, public_key));
+    }
+
+    pub fn len(self: &Self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    /* check s_1*G + a_2*s_2*G + ... == R_1 + a_2*R_2 + ... +
+    e_1*P_1 + a_2*e_2*P_2 + ... with random weights a_i, so a
+    single invalid signature cannot be cancelled out by another */
+    pub fn verify(self: &Self) -> bool {
+        let mut rng = rand::thread_rng();
+        let mut s_sum = Scalar::ZERO;
+        let mut terms: Vec<(ProjectivePoint, Scalar)> = Vec::with_capacity(
+            self.entries.len() * 2 + 1
+        );
+        for (idx, (output_hash, signature, public_key)) in self.entries.iter().enumerate() {
+            let bytes = signature.to_bytes();
+            let (r_bytes, s_bytes) = bytes.split_at(XONLY_PUBLIC_KEY_SIZE);
+            let r_point = AffinePoint::decompact(FieldBytes::from_slice(r_bytes));
+            if r_point.is_none().into() {
+                return false;
+            }
+            let s = Scalar::from_repr(*FieldBytes::from_slice(s_bytes));
+            if s.is_none().into() {
+                return false;
+            }
+            let e = <Scalar as Reduce<k256::U256>>::reduce_bytes(
+                &tagged_hash(b"BIP0340/challenge")
+                    .chain_update(r_bytes)
+                    .chain_update(public_key.to_bytes())
+                    .chain_update(output_hash.as_bytes())
+                    .finalize(),
+            );
+            let weight = if idx == 0 {
+                Scalar::ONE
+            } else {
+                Scalar::random(&mut rng)
+            };
+            s_sum += weight * s.unwrap();
+            terms.push((ProjectivePoint::from(r_point.unwrap()), weight));
+            terms.push((ProjectivePoint::from(*public_key.0.as_affine()), weight * e));
+        }
+        terms.push((ProjectivePoint::GENERATOR, -s_sum));
+        return bool::from(ProjectivePoint::lincomb_ext(terms.as_slice()).is_identity());
+    }
+}
+
+fn tagged_hash(tag: &[u8]) -> Sha256 {
+    let tag_hash = Sha256::digest(tag);
+    return Sha256::new()
+            .chain_update(tag_hash)
+            .chain_update(tag_hash);
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
-pub struct PublicKey(VerifyingKey<Secp256k1>);
+pub struct PublicKey(
+    #[serde(with = "verifykey_serde")]
+    VerifyingKey<Secp256k1>
+);
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+impl PublicKey {
+
+    pub fn to_bytes(self: &Self) -> [u8; PUBLIC_KEY_SIZE] {
+        let compressed = self.0.to_encoded_point(true);
+        return compressed.as_bytes().try_into().expect("BUG: Impossible");
+    }
+
+    //only the compressed encoding is accepted
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BtcError> {
+        if bytes.len() != PUBLIC_KEY_SIZE {
+            return Err(BtcError::InvalidPublicKey);
+        }
+        let key = VerifyingKey::from_sec1_bytes(bytes)
+                        .map_err(|_| BtcError::InvalidPublicKey)?;
+        return Ok(PublicKey(key));
+    }
+
+    pub fn to_hex(self: &Self) -> String {
+        return hex::encode(self.to_bytes());
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, BtcError> {
+        let bytes = hex::decode(s.trim()).map_err(|_| BtcError::InvalidPublicKey)?;
+        return Self::from_bytes(&bytes);
+    }
+
+    //SubjectPublicKeyInfo PEM encoding
+    pub fn to_pem(self: &Self) -> Result<String, BtcError> {
+        return self.0.to_public_key_pem(LineEnding::LF)
+                    .map_err(|_| BtcError::InvalidPublicKey);
+    }
+
+    pub fn from_pem(s: &str) -> Result<Self, BtcError> {
+        let key = VerifyingKey::from_public_key_pem(s)
+                        .map_err(|_| BtcError::InvalidPublicKey)?;
+        return Ok(PublicKey(key));
+    }
+
+    //BIP340 x-only key, the x coordinate of the even-y point
+    pub fn x_only(self: &Self) -> XOnlyPublicKey {
+        let point = self.0.to_encoded_point(true);
+        let key = SchnorrVerifyingKey::from_bytes(point.x().expect("BUG: Impossible"))
+                        .expect("BUG: Impossible");
+        return XOnlyPublicKey(key);
+    }
+
+    //ripemd160(sha256(compressed sec1 key))
+    pub fn pubkey_hash(self: &Self) -> PubKeyHash {
+        let sha = Sha256::digest(self.to_bytes());
+        return PubKeyHash(Ripemd160::digest(sha).into());
+    }
+
+    pub fn address(self: &Self, network: Network) -> Address {
+        return Address::new(network, self.pubkey_hash());
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XOnlyPublicKey(
+    #[serde(with = "xonly_serde")]
+    SchnorrVerifyingKey
+);
+
+impl XOnlyPublicKey {
+
+    pub fn to_bytes(self: &Self) -> [u8; XONLY_PUBLIC_KEY_SIZE] {
+        return self.0.to_bytes().into();
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BtcError> {
+        if bytes.len() != XONLY_PUBLIC_KEY_SIZE {
+            return Err(BtcError::InvalidPublicKey);
+        }
+        let key = SchnorrVerifyingKey::from_bytes(bytes)
+                        .map_err(|_| BtcError::InvalidPublicKey)?;
+        return Ok(XOnlyPublicKey(key));
+    }
+
+    pub fn to_hex(self: &Self) -> String {
+        return hex::encode(self.to_bytes());
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, BtcError> {
+        let bytes = hex::decode(s).map_err(|_| BtcError::InvalidPublicKey)?;
+        return Self::from_bytes(&bytes);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PubKeyHash(pub [u8; 20]);
+
+impl fmt::Display for PubKeyHash {
+
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", hex::encode(self.0));
+    }
+}
+
+// pay-to-pubkey-hash address, Base58Check encoded
+// as version byte || pubkey hash || 4 byte checksum
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub network: Network,
+    pub hash: PubKeyHash,
+}
+
+impl Address {
+
+    pub fn new(network: Network, hash: PubKeyHash) -> Self {
+        return Address { network, hash };
+    }
+}
+
+impl fmt::Display for Address {
+
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        let encoded = bs58::encode(self.hash.0)
+                            .with_check_version(self.network.address_prefix())
+                            .into_string();
+        return write!(f, "{}", encoded);
+    }
+}
+
+impl FromStr for Address {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        //decoded bytes still carry the version byte
+        let bytes = bs58::decode(s)
+                        .with_check(None)
+                        .into_vec()
+                        .map_err(|_| BtcError::InvalidAddress)?;
+        if bytes.len() != 21 {
+            return Err(BtcError::InvalidAddress);
+        }
+        let network = Network::from_address_prefix(bytes[0])
+                        .ok_or(BtcError::InvalidAddress)?;
+        let hash: [u8; 20] = bytes[1..].try_into()
+                        .map_err(|_| BtcError::InvalidAddress)?;
+        return Ok(Address::new(network, PubKeyHash(hash)));
+    }
+}
+
+//secret bytes are zeroized on drop by SigningKey,
+//Clone and Debug are left out so they cannot leak
+#[derive(Serialize, Deserialize)]
 pub struct PrivateKey(
     #[serde(with = "signkey_serde")]
     SigningKey<Secp256k1>
@@ -44,9 +378,67 @@ impl PrivateKey {
     }
 
     pub fn public_key(self: &Self) -> PublicKey {
-        return PublicKey(self.0.verifying_key().clone());
+        return PublicKey(*self.0.verifying_key());
+    }
+
+    pub fn to_bytes(self: &Self) -> Zeroizing<[u8; 32]> {
+        return Zeroizing::new(self.0.to_bytes().into());
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BtcError> {
+        let key = SigningKey::from_slice(bytes)
+                        .map_err(|_| BtcError::InvalidPrivateKey)?;
+        return Ok(PrivateKey(key));
+    }
+
+    pub fn to_hex(self: &Self) -> Zeroizing<String> {
+        return Zeroizing::new(hex::encode(self.to_bytes().as_slice()));
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, BtcError> {
+        let bytes = Zeroizing::new(
+            hex::decode(s.trim()).map_err(|_| BtcError::InvalidPrivateKey)?
+        );
+        return Self::from_bytes(&bytes);
+    }
+
+    //PKCS#8 PEM encoding
+    pub fn to_pem(self: &Self) -> Result<Zeroizing<String>, BtcError> {
+        return self.0.to_pkcs8_pem(LineEnding::LF)
+                    .map_err(|_| BtcError::InvalidPrivateKey);
+    }
+
+    pub fn from_pem(s: &str) -> Result<Self, BtcError> {
+        let key = SigningKey::from_pkcs8_pem(s)
+                        .map_err(|_| BtcError::InvalidPrivateKey)?;
+        return Ok(PrivateKey(key));
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "PrivateKey(<redacted>)");
+    }
+}
+
+impl ZeroizeOnDrop for PrivateKey {}
+
+impl Saveable for PrivateKey {
+
+    fn load<I: Read>(reader: I) -> IOResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to deserialize PrivateKey")
+        })
     }
 
+    fn save<O: Write>(self: &Self, writer: O) -> IOResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to serialize PrivateKey")
+        })
+    }
 }
 
 mod signkey_serde {
@@ -58,7 +450,8 @@ mod signkey_serde {
      where 
         S: serde::Serializer,
     {
-        return serializer.serialize_bytes(&key.to_bytes());
+        let bytes = super::Zeroizing::new(key.to_bytes());
+        return serializer.serialize_bytes(&bytes);
     }
 
     pub fn deserialize<'de, D>(
@@ -66,8 +459,107 @@ mod signkey_serde {
     ) -> Result<super::SigningKey<super::Secp256k1>, D::Error>
     where 
         D: serde::Deserializer<'de>,
+    {
+        let bytes = super::Zeroizing::new(Vec::<u8>::deserialize(deserializer)?);
+        return super::SigningKey::from_slice(&bytes)
+                    .map_err(|_| serde::de::Error::custom(super::BtcError::InvalidPrivateKey));
+    }
+}
+
+mod verifykey_serde {
+    use serde::Deserialize;
+    pub fn serialize<S>(
+        key: &super::VerifyingKey<super::Secp256k1>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        return serializer.serialize_bytes(key.to_encoded_point(true).as_bytes());
+    }
+
+    pub fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<super::VerifyingKey<super::Secp256k1>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
     {
         let bytes: Vec<u8> = Vec::<u8>::deserialize(deserializer)?;
-        return Ok(super::SigningKey::from_slice(&bytes).unwrap());
+        return super::PublicKey::from_bytes(&bytes)
+                    .map(|key| key.0)
+                    .map_err(serde::de::Error::custom);
     }
-}
\ No newline at end of file
+}
+
+mod signature_serde {
+    use serde::Deserialize;
+    pub fn serialize<S>(
+        signature: &super::ECDSASignature<super::Secp256k1>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        return serializer.serialize_bytes(&signature.to_bytes());
+    }
+
+    pub fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<super::ECDSASignature<super::Secp256k1>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes: Vec<u8> = Vec::<u8>::deserialize(deserializer)?;
+        return super::ECDSASignature::from_slice(&bytes)
+                    .map_err(serde::de::Error::custom);
+    }
+}
+
+mod schnorr_serde {
+    use serde::Deserialize;
+    pub fn serialize<S>(
+        signature: &super::SchnorrSignature,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        return serializer.serialize_bytes(&signature.to_bytes());
+    }
+
+    pub fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<super::SchnorrSignature, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes: Vec<u8> = Vec::<u8>::deserialize(deserializer)?;
+        return super::SchnorrSignature::try_from(bytes.as_slice())
+                    .map_err(serde::de::Error::custom);
+    }
+}
+
+mod xonly_serde {
+    use serde::Deserialize;
+    pub fn serialize<S>(
+        key: &super::SchnorrVerifyingKey,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        return serializer.serialize_bytes(&key.to_bytes());
+    }
+
+    pub fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<super::SchnorrVerifyingKey, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes: Vec<u8> = Vec::<u8>::deserialize(deserializer)?;
+        return super::XOnlyPublicKey::from_bytes(&bytes)
+                    .map(|key| key.0)
+                    .map_err(serde::de::Error::custom);
+    }
+}
@@ -21,6 +21,76 @@ InvalidSignature,
 InvalidPublicKey,
 #[error("Invalid private key")]
 InvalidPrivateKey,
+#[error("Invalid address")]
+InvalidAddress,
+#[error("Invalid passphrase")]
+InvalidPassphrase,
+#[error("Invalid keystore")]
+InvalidKeystore,
+#[error("Key not found")]
+KeyNotFound,
+#[error("Duplicate key label")]
+DuplicateKeyLabel,
+#[error("Invalid mnemonic")]
+InvalidMnemonic,
+#[error("Invalid derivation path")]
+InvalidDerivationPath,
+#[error("Invalid extended key")]
+InvalidExtendedKey,
+#[error("Insufficient funds")]
+InsufficientFunds,
+#[error("Amount or fee is out of range")]
+InvalidAmount,
+#[error("Wallet is watch-only")]
+WatchOnly,
+#[error("Invalid partially signed transaction")]
+InvalidPsbt,
+#[error("Partially signed transaction is missing signatures")]
+IncompletePsbt,
+#[error("Invalid coin selection strategy")]
+InvalidCoinSelection,
+#[error("Too many unconfirmed ancestors or descendants")]
+MempoolChainLimit,
+#[error("Replacement does not pay a higher fee and fee rate")]
+InsufficientReplacementFee,
+#[error("Replacement would evict too many transactions")]
+TooManyReplacements,
+#[error("Fee rate is below the mempool minimum")]
+InsufficientFee,
+#[error("Failed to write to the block store")]
+BlockStoreFailure,
+#[error("Snapshot does not match the chain")]
+InvalidSnapshot,
+#[error("Block is not available")]
+BlockNotAvailable,
+#[error("Chain does not have more work")]
+InsufficientChainWork,
+#[error("Chain is inconsistent after a failed reorganization")]
+ChainInconsistent,
+#[error("Peer is on a different network")]
+WrongNetwork,
+#[error("Peer protocol version is not supported")]
+IncompatibleProtocol,
+#[error("Peer did not complete the handshake")]
+HandshakeFailed,
+#[error("Message is larger than its type allows")]
+MessageTooLarge,
+#[error("Message is not of the size class in its frame header")]
+MessageSizeClassMismatch,
+#[error("Message is not of the type in its frame header")]
+MessageTypeMismatch,
+#[error("Too many messages of unknown types")]
+TooManyUnknownMessages,
+#[error("File is not in a known format")]
+UnknownFileFormat,
+#[error("File format version is not supported")]
+UnsupportedFileVersion,
+#[error("File is truncated")]
+TruncatedFile,
+#[error("File checksum does not match its contents")]
+FileChecksumMismatch,
+#[error("Block store format version is not supported")]
+UnsupportedStoreVersion,
 }
 
 pub type Result<T> = std::result::Result<T, BtcError>;
\ No newline at end of file
@@ -0,0 +1,360 @@
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use k256::elliptic_curve::PrimeField;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::{FieldBytes, ProjectivePoint, Scalar};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha512;
+use std::fmt;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+use crate::crypto::{PrivateKey, PublicKey};
+use crate::error::{BtcError, Result};
+use crate::network::Network;
+
+type HmacSha512 = Hmac<Sha512>;
+
+//child indexes at or above this are hardened
+pub const HARDENED_OFFSET: u32 = 1 << 31;
+//length of a serialized extended key before Base58Check
+const EXTENDED_KEY_SIZE: usize = 78;
+
+//generate a new 12 word BIP39 mnemonic
+pub fn generate_mnemonic() -> Mnemonic {
+    let mut entropy = Zeroizing::new([0u8; 16]);
+    rand::thread_rng().fill_bytes(entropy.as_mut());
+    return Mnemonic::from_entropy(entropy.as_ref()).expect("BUG: Impossible");
+}
+
+pub fn parse_mnemonic(phrase: &str) -> Result<Mnemonic> {
+    return Mnemonic::parse(phrase).map_err(|_| BtcError::InvalidMnemonic);
+}
+
+// path of child indexes from the master key, e.g. m/44'/0'/0'/1/5
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DerivationPath(Vec<u32>);
+
+impl DerivationPath {
+
+    pub fn indexes(self: &Self) -> &[u32] {
+        return &self.0;
+    }
+
+    pub fn child(self: &Self, index: u32) -> Self {
+        let mut path = self.0.clone();
+        path.push(index);
+        return DerivationPath(path);
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split('/');
+        if parts.next() != Some("m") {
+            return Err(BtcError::InvalidDerivationPath);
+        }
+        let mut path = Vec::new();
+        for part in parts {
+            let (number, hardened) = match part.strip_suffix(['\'', 'h']) {
+                Some(number) => (number, true),
+                None => (part, false),
+            };
+            let index: u32 = number.parse()
+                                .map_err(|_| BtcError::InvalidDerivationPath)?;
+            if index >= HARDENED_OFFSET {
+                return Err(BtcError::InvalidDerivationPath);
+            }
+            path.push(if hardened { index + HARDENED_OFFSET } else { index });
+        }
+        return Ok(DerivationPath(path));
+    }
+}
+
+impl fmt::Display for DerivationPath {
+
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            if *index >= HARDENED_OFFSET {
+                write!(f, "/{}'", index - HARDENED_OFFSET)?;
+            } else {
+                write!(f, "/{}", index)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+//fields shared by extended private and public keys
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+struct ExtendedKeyInfo {
+    network: Network,
+    depth: u8,
+    parent_fingerprint: [u8; 4],
+    child_number: u32,
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKeyInfo {
+
+    //depth is a single byte, keys at depth 255 have no children
+    fn child(self: &Self, parent: &PublicKey, index: u32, chain_code: &[u8]) -> Result<Self> {
+        let depth = self.depth.checked_add(1).ok_or(BtcError::InvalidDerivationPath)?;
+        return Ok(ExtendedKeyInfo {
+            network: self.network,
+            depth,
+            parent_fingerprint: fingerprint(parent),
+            child_number: index,
+            chain_code: chain_code.try_into().expect("BUG: Impossible"),
+        });
+    }
+
+    //version || depth || fingerprint || child number || chain code || key
+    fn encode(self: &Self, version: [u8; 4], key: &[u8]) -> Zeroizing<Vec<u8>> {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(EXTENDED_KEY_SIZE));
+        bytes.extend_from_slice(&version);
+        bytes.push(self.depth);
+        bytes.extend_from_slice(&self.parent_fingerprint);
+        bytes.extend_from_slice(&self.child_number.to_be_bytes());
+        bytes.extend_from_slice(&self.chain_code);
+        bytes.extend_from_slice(key);
+        return bytes;
+    }
+
+    fn decode(bytes: &[u8], network: Network) -> Self {
+        return ExtendedKeyInfo {
+            network,
+            depth: bytes[4],
+            parent_fingerprint: bytes[5..9].try_into().expect("BUG: Impossible"),
+            child_number: u32::from_be_bytes(bytes[9..13].try_into().expect("BUG: Impossible")),
+            chain_code: bytes[13..45].try_into().expect("BUG: Impossible"),
+        };
+    }
+}
+
+fn private_version(network: Network) -> [u8; 4] {
+    return match network {
+        Network::Mainnet => [0x04, 0x88, 0xad, 0xe4],
+        Network::Testnet => [0x04, 0x35, 0x83, 0x94],
+    };
+}
+
+fn public_version(network: Network) -> [u8; 4] {
+    return match network {
+        Network::Mainnet => [0x04, 0x88, 0xb2, 0x1e],
+        Network::Testnet => [0x04, 0x35, 0x87, 0xcf],
+    };
+}
+
+//first 4 bytes of the key's hash160
+fn fingerprint(public_key: &PublicKey) -> [u8; 4] {
+    return public_key.pubkey_hash().0[..4].try_into().expect("BUG: Impossible");
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> Zeroizing<[u8; 64]> {
+    let mut mac = HmacSha512::new_from_slice(key).expect("BUG: Impossible");
+    for part in data {
+        mac.update(part);
+    }
+    return Zeroizing::new(mac.finalize().into_bytes().into());
+}
+
+//left half of a BIP32 hmac output as a scalar, rejecting values >= n
+fn parse_scalar(bytes: &[u8]) -> Result<Scalar> {
+    return Option::from(Scalar::from_repr(*FieldBytes::from_slice(bytes)))
+                .ok_or(BtcError::InvalidPrivateKey);
+}
+
+fn decode_base58(s: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let bytes = Zeroizing::new(bs58::decode(s)
+                    .with_check(None)
+                    .into_vec()
+                    .map_err(|_| BtcError::InvalidExtendedKey)?);
+    if bytes.len() != EXTENDED_KEY_SIZE {
+        return Err(BtcError::InvalidExtendedKey);
+    }
+    return Ok(bytes);
+}
+
+// BIP32 extended private key
+pub struct ExtendedPrivateKey {
+    info: ExtendedKeyInfo,
+    key: PrivateKey,
+}
+
+impl ExtendedPrivateKey {
+
+    pub fn from_seed(seed: &[u8], network: Network) -> Result<Self> {
+        let output = hmac_sha512(b"Bitcoin seed", &[seed]);
+        let key = PrivateKey::from_bytes(&output[..32])?;
+        return Ok(ExtendedPrivateKey {
+            info: ExtendedKeyInfo {
+                network,
+                depth: 0,
+                parent_fingerprint: [0u8; 4],
+                child_number: 0,
+                chain_code: output[32..].try_into().expect("BUG: Impossible"),
+            },
+            key,
+        });
+    }
+
+    pub fn from_mnemonic(mnemonic: &Mnemonic, passphrase: &str,
+                         network: Network) -> Result<Self> {
+        let seed = Zeroizing::new(mnemonic.to_seed(passphrase));
+        return Self::from_seed(seed.as_ref(), network);
+    }
+
+    pub fn private_key(self: &Self) -> &PrivateKey {
+        return &self.key;
+    }
+
+    pub fn into_private_key(self: Self) -> PrivateKey {
+        return self.key;
+    }
+
+    pub fn public_key(self: &Self) -> PublicKey {
+        return self.key.public_key();
+    }
+
+    pub fn network(self: &Self) -> Network {
+        return self.info.network;
+    }
+
+    pub fn extended_public_key(self: &Self) -> ExtendedPublicKey {
+        return ExtendedPublicKey {
+            info: self.info,
+            key: self.key.public_key(),
+        };
+    }
+
+    pub fn derive_child(self: &Self, index: u32) -> Result<Self> {
+        let parent_public = self.key.public_key();
+        let parent_secret = self.key.to_bytes();
+        let output = if index >= HARDENED_OFFSET {
+            hmac_sha512(&self.info.chain_code,
+                        &[&[0u8], parent_secret.as_slice(), &index.to_be_bytes()])
+        } else {
+            hmac_sha512(&self.info.chain_code,
+                        &[&parent_public.to_bytes(), &index.to_be_bytes()])
+        };
+        let tweak = parse_scalar(&output[..32])?;
+        let child_secret = tweak + parse_scalar(parent_secret.as_slice())?;
+        let child_bytes = Zeroizing::new(child_secret.to_bytes());
+        return Ok(ExtendedPrivateKey {
+            info: self.info.child(&parent_public, index, &output[32..])?,
+            key: PrivateKey::from_bytes(&child_bytes)?,
+        });
+    }
+
+    pub fn derive_path(self: &Self, path: &DerivationPath) -> Result<Self> {
+        let mut key = ExtendedPrivateKey {
+            info: self.info,
+            key: PrivateKey::from_bytes(self.key.to_bytes().as_slice())?,
+        };
+        for index in path.indexes() {
+            key = key.derive_child(*index)?;
+        }
+        return Ok(key);
+    }
+
+    pub fn to_base58(self: &Self) -> Zeroizing<String> {
+        let mut key = Zeroizing::new([0u8; 33]);
+        key[1..].copy_from_slice(self.key.to_bytes().as_slice());
+        let bytes = self.info.encode(private_version(self.info.network), key.as_ref());
+        return Zeroizing::new(bs58::encode(bytes.as_slice()).with_check().into_string());
+    }
+
+    pub fn from_base58(s: &str) -> Result<Self> {
+        let bytes = decode_base58(s)?;
+        let network = [Network::Mainnet, Network::Testnet].into_iter()
+                        .find(|network| bytes[..4] == private_version(*network))
+                        .ok_or(BtcError::InvalidExtendedKey)?;
+        if bytes[45] != 0 {
+            return Err(BtcError::InvalidExtendedKey);
+        }
+        return Ok(ExtendedPrivateKey {
+            info: ExtendedKeyInfo::decode(&bytes, network),
+            key: PrivateKey::from_bytes(&bytes[46..])?,
+        });
+    }
+}
+
+impl fmt::Debug for ExtendedPrivateKey {
+
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "ExtendedPrivateKey(<redacted>)");
+    }
+}
+
+// BIP32 extended public key, derives non-hardened children only
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExtendedPublicKey {
+    info: ExtendedKeyInfo,
+    key: PublicKey,
+}
+
+impl ExtendedPublicKey {
+
+    pub fn public_key(self: &Self) -> &PublicKey {
+        return &self.key;
+    }
+
+    pub fn network(self: &Self) -> Network {
+        return self.info.network;
+    }
+
+    pub fn derive_child(self: &Self, index: u32) -> Result<Self> {
+        if index >= HARDENED_OFFSET {
+            return Err(BtcError::InvalidDerivationPath);
+        }
+        let output = hmac_sha512(&self.info.chain_code,
+                                 &[&self.key.to_bytes(), &index.to_be_bytes()]);
+        let tweak = parse_scalar(&output[..32])?;
+        let parent = k256::PublicKey::from_sec1_bytes(&self.key.to_bytes())
+                        .map_err(|_| BtcError::InvalidPublicKey)?;
+        let child = ProjectivePoint::GENERATOR * tweak + parent.to_projective();
+        let child = k256::PublicKey::from_affine(child.to_affine())
+                        .map_err(|_| BtcError::InvalidPublicKey)?;
+        return Ok(ExtendedPublicKey {
+            info: self.info.child(&self.key, index, &output[32..])?,
+            key: PublicKey::from_bytes(child.to_encoded_point(true).as_bytes())?,
+        });
+    }
+
+    //path is relative to this key, e.g. m/0/5
+    pub fn derive_path(self: &Self, path: &DerivationPath) -> Result<Self> {
+        let mut key = self.clone();
+        for index in path.indexes() {
+            key = key.derive_child(*index)?;
+        }
+        return Ok(key);
+    }
+}
+
+impl fmt::Display for ExtendedPublicKey {
+
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = self.info.encode(public_version(self.info.network), &self.key.to_bytes());
+        return write!(f, "{}", bs58::encode(bytes.as_slice()).with_check().into_string());
+    }
+}
+
+impl FromStr for ExtendedPublicKey {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = decode_base58(s)?;
+        let network = [Network::Mainnet, Network::Testnet].into_iter()
+                        .find(|network| bytes[..4] == public_version(*network))
+                        .ok_or(BtcError::InvalidExtendedKey)?;
+        return Ok(ExtendedPublicKey {
+            info: ExtendedKeyInfo::decode(&bytes, network),
+            key: PublicKey::from_bytes(&bytes[45..])?,
+        });
+    }
+}
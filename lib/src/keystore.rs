@@ -0,0 +1,253 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read,
+    Result as IOResult, Write};
+use zeroize::Zeroizing;
+
+use crate::crypto::{PrivateKey, PublicKey};
+use crate::error::{BtcError, Result};
+use crate::util::Saveable;
+
+//current keystore file format version
+pub const KEYSTORE_VERSION: u32 = 1;
+//plaintext encrypted with every passphrase to check it
+//before keys are added or decrypted
+const PASSPHRASE_CHECK: &[u8] = b"btclib keystore";
+
+//argon2id parameters, stored with the keystore so they can be
+//raised later without breaking existing files
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KdfParams {
+    pub salt: Vec<u8>,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+
+    fn generate() -> Self {
+        let mut salt = vec![0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        return KdfParams {
+            salt,
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        };
+    }
+
+    //derive a 256-bit cipher key from the passphrase
+    fn derive_key(self: &Self, passphrase: &str) -> Result<Zeroizing<[u8; 32]>> {
+        let params = Params::new(self.memory_kib, self.iterations,
+                                 self.parallelism, Some(32))
+                        .map_err(|_| BtcError::InvalidKeystore)?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let mut key = Zeroizing::new([0u8; 32]);
+        argon2.hash_password_into(passphrase.as_bytes(), &self.salt, key.as_mut())
+            .map_err(|_| BtcError::InvalidKeystore)?;
+        return Ok(key);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Ciphertext {
+    nonce: [u8; 12],
+    data: Vec<u8>,
+}
+
+impl Ciphertext {
+
+    fn seal(key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Self {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let data = cipher.encrypt(Nonce::from_slice(&nonce),
+                                  Payload { msg: plaintext, aad })
+                        .expect("BUG: Impossible");
+        return Ciphertext { nonce, data };
+    }
+
+    fn open(self: &Self, key: &[u8; 32], aad: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let plaintext = cipher.decrypt(Nonce::from_slice(&self.nonce),
+                                       Payload { msg: &self.data, aad })
+                            .map_err(|_| BtcError::InvalidPassphrase)?;
+        return Ok(Zeroizing::new(plaintext));
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeystoreEntry {
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+    pub public_key: PublicKey,
+    secret: Ciphertext,
+}
+
+impl KeystoreEntry {
+
+    //label and public key are bound to the ciphertext so
+    //they cannot be swapped between entries
+    fn aad(label: &str, public_key: &PublicKey) -> Vec<u8> {
+        let mut aad = label.as_bytes().to_vec();
+        aad.extend_from_slice(&public_key.to_bytes());
+        return aad;
+    }
+
+    fn decrypt(self: &Self, key: &[u8; 32]) -> Result<PrivateKey> {
+        let aad = Self::aad(&self.label, &self.public_key);
+        let bytes = self.secret.open(key, &aad)?;
+        let private_key = PrivateKey::from_bytes(&bytes)?;
+        if private_key.public_key() != self.public_key {
+            return Err(BtcError::InvalidKeystore);
+        }
+        return Ok(private_key);
+    }
+}
+
+// set of private keys encrypted with chacha20poly1305 under
+// an argon2id key derived from a passphrase
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Keystore {
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    kdf: KdfParams,
+    check: Ciphertext,
+    entries: Vec<KeystoreEntry>,
+    //optional HD wallet seed
+    #[serde(default)]
+    seed: Option<Ciphertext>,
+}
+
+impl Keystore {
+
+    pub fn new(passphrase: &str) -> Result<Self> {
+        let kdf = KdfParams::generate();
+        let key = kdf.derive_key(passphrase)?;
+        return Ok(Keystore {
+            version: KEYSTORE_VERSION,
+            created_at: Utc::now(),
+            check: Ciphertext::seal(&key, PASSPHRASE_CHECK, &[]),
+            kdf,
+            entries: Vec::new(),
+            seed: None,
+        });
+    }
+
+    pub fn entries(self: &Self) -> &[KeystoreEntry] {
+        return &self.entries;
+    }
+
+    //derive the cipher key and make sure it matches the keystore
+    fn unlock(self: &Self, passphrase: &str) -> Result<Zeroizing<[u8; 32]>> {
+        let key = self.kdf.derive_key(passphrase)?;
+        self.check.open(&key, &[])?;
+        return Ok(key);
+    }
+
+    pub fn add_key(self: &mut Self, passphrase: &str,
+                   label: &str, private_key: &PrivateKey) -> Result<()> {
+        if self.entries.iter().any(|entry| entry.label == label) {
+            return Err(BtcError::DuplicateKeyLabel);
+        }
+        let key = self.unlock(passphrase)?;
+        let public_key = private_key.public_key();
+        let aad = KeystoreEntry::aad(label, &public_key);
+        self.entries.push(KeystoreEntry {
+            label: label.to_owned(),
+            created_at: Utc::now(),
+            secret: Ciphertext::seal(&key, private_key.to_bytes().as_slice(), &aad),
+            public_key,
+        });
+        return Ok(());
+    }
+
+    //decrypt a single key by label
+    pub fn export_key(self: &Self, passphrase: &str, label: &str) -> Result<PrivateKey> {
+        let entry = self.entries.iter()
+                        .find(|entry| entry.label == label)
+                        .ok_or(BtcError::KeyNotFound)?;
+        let key = self.unlock(passphrase)?;
+        return entry.decrypt(&key);
+    }
+
+    //decrypt all keys
+    pub fn keys(self: &Self, passphrase: &str) -> Result<Vec<(String, PrivateKey)>> {
+        let key = self.unlock(passphrase)?;
+        return self.entries.iter()
+                    .map(|entry| Ok((entry.label.clone(), entry.decrypt(&key)?)))
+                    .collect();
+    }
+
+    pub fn has_seed(self: &Self) -> bool {
+        return self.seed.is_some();
+    }
+
+    pub fn set_seed(self: &mut Self, passphrase: &str, seed: &[u8]) -> Result<()> {
+        let key = self.unlock(passphrase)?;
+        self.seed = Some(Ciphertext::seal(&key, seed, b"seed"));
+        return Ok(());
+    }
+
+    pub fn seed(self: &Self, passphrase: &str) -> Result<Zeroizing<Vec<u8>>> {
+        let seed = self.seed.as_ref().ok_or(BtcError::KeyNotFound)?;
+        let key = self.unlock(passphrase)?;
+        return seed.open(&key, b"seed");
+    }
+
+    //re-encrypt every key under a fresh salt and the new passphrase
+    pub fn change_passphrase(self: &mut Self, old_passphrase: &str,
+                             new_passphrase: &str) -> Result<()> {
+        let old_key = self.unlock(old_passphrase)?;
+        let kdf = KdfParams::generate();
+        let new_key = kdf.derive_key(new_passphrase)?;
+
+        let mut entries = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            let private_key = entry.decrypt(&old_key)?;
+            let aad = KeystoreEntry::aad(&entry.label, &entry.public_key);
+            entries.push(KeystoreEntry {
+                secret: Ciphertext::seal(&new_key, private_key.to_bytes().as_slice(), &aad),
+                ..entry.clone()
+            });
+        }
+
+        let seed = match &self.seed {
+            Some(seed) => Some(Ciphertext::seal(&new_key, &seed.open(&old_key, b"seed")?, b"seed")),
+            None => None,
+        };
+
+        self.check = Ciphertext::seal(&new_key, PASSPHRASE_CHECK, &[]);
+        self.seed = seed;
+        self.kdf = kdf;
+        self.entries = entries;
+        return Ok(());
+    }
+}
+
+impl Saveable for Keystore {
+
+    fn load<I: Read>(reader: I) -> IOResult<Self> {
+        let keystore: Keystore = ciborium::de::from_reader(reader).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to deserialize Keystore")
+        })?;
+        if keystore.version != KEYSTORE_VERSION {
+            return Err(IOError::new(IOErrorKind::InvalidData,
+                "Unsupported Keystore version"));
+        }
+        return Ok(keystore);
+    }
+
+    fn save<O: Write>(self: &Self, writer: O) -> IOResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to serialize Keystore")
+        })
+    }
+}
@@ -1,3 +1,7 @@
+#![allow(clippy::needless_return,
+        clippy::needless_arbitrary_self_type,
+        clippy::self_named_constructors,
+        clippy::manual_div_ceil)]
 use uint::construct_uint;
 use serde::{Serialize, Deserialize};
 construct_uint!{
@@ -24,6 +28,26 @@ pub const MIN_TARGET: U256 = U256([
 pub const DIFFICULTY_UPDATE_INETRVAL: u64 = 50;
 //max mempool tx age in seconds
 pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
+//max block size in bytes
+pub const MAX_BLOCK_SIZE: u64 = 1_000_000;
+//default mempool cap in bytes
+pub const MAX_MEMPOOL_SIZE: u64 = 300_000_000;
+//min fee per byte for a tx to enter the mempool
+pub const MIN_RELAY_FEE_RATE: u64 = 1;
+//seconds for the rolling min fee rate to halve after evictions
+pub const ROLLING_FEE_HALFLIFE: u64 = 600;
+//max unconfirmed ancestors or descendants of a mempool tx,
+//counting the tx itself
+pub const MAX_MEMPOOL_ANCESTORS: usize = 25;
+pub const MAX_MEMPOOL_DESCENDANTS: usize = 25;
+//max mempool tx a replacement may evict, descendants included
+pub const MAX_REPLACEMENT_EVICTIONS: usize = 100;
+//recent blocks whose fee rates are kept for fee estimation
+pub const FEE_HISTORY_BLOCKS: usize = 24;
+//fewest recent blocks a pruned node keeps with their undo data
+pub const MIN_BLOCKS_TO_KEEP: u64 = 288;
+//most headers sent in one Headers message
+pub const MAX_HEADERS_PER_MESSAGE: u32 = 2000;
 
 pub mod sha256;
 pub mod types;
@@ -31,3 +55,6 @@ pub mod util;
 pub mod crypto;
 pub mod error;
 pub mod network;
+pub mod keystore;
+pub mod hd;
+pub mod store;
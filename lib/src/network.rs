@@ -1,11 +1,194 @@
 // network.rs
 use serde::{Deserialize, Serialize};
-use std::io::{Error as IOError, Read, Write};
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read, Result as IOResult, Write};
+use std::ops::BitOr;
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder, Framed};
 
 use crate::crypto::PublicKey;
-use crate::types::{Block, Transactions, TransactionsOutput};
+use crate::error::BtcError;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, OutputRecord, Transactions, TransactionsOutput, TxLocation};
+
+//protocol version spoken by this build, bumped whenever messages
+//change in a way older peers would not understand. 2 has the size
+//class of each message in its frame header, 3 its type as well
+pub const PROTOCOL_VERSION: u32 = 3;
+//oldest protocol version we still talk to, frames from before 3
+//can not be read
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+//most bytes of any one message, lists of outputs can outgrow a
+//block
+pub const MAX_MESSAGE_SIZE: u64 = 32 * 1024 * 1024;
+//most bytes of a message carrying no block, transaction or list
+const MAX_SMALL_MESSAGE_SIZE: u64 = 4 * 1024;
+//room for the variant and option around a block or transaction
+const MESSAGE_OVERHEAD: u64 = 1024;
+//most bytes of one header or hash in a list of them
+const MAX_HEADER_SIZE: u64 = 256;
+//size class byte, type byte and length in front of every message
+const FRAME_HEADER_SIZE: usize = 10;
+//message types this build knows, the type byte of every variant
+//of Message is below it
+const MESSAGE_TYPES: u8 = 29;
+//frames of unknown types recieve skips in a row before it gives
+//up on the peer
+const MAX_UNKNOWN_FRAMES: usize = 100;
+
+/* how large a message may be, sent in front of it so a frame
+longer than its class allows is refused before its body is read.
+A message must come in the class of its type */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeClass {
+    Small,
+    Headers,
+    Block,
+    List,
+}
+
+impl SizeClass {
+
+    //most bytes of a message in this class
+    pub fn max_size(self: Self) -> u64 {
+        return match self {
+            SizeClass::Small => MAX_SMALL_MESSAGE_SIZE,
+            SizeClass::Headers => {
+                crate::MAX_HEADERS_PER_MESSAGE as u64 * MAX_HEADER_SIZE + MESSAGE_OVERHEAD
+            }
+            SizeClass::Block => crate::MAX_BLOCK_SIZE + MESSAGE_OVERHEAD,
+            SizeClass::List => MAX_MESSAGE_SIZE,
+        };
+    }
+
+    fn to_byte(self: Self) -> u8 {
+        return self as u8;
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        return match byte {
+            0 => Some(SizeClass::Small),
+            1 => Some(SizeClass::Headers),
+            2 => Some(SizeClass::Block),
+            3 => Some(SizeClass::List),
+            _ => None,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+}
+
+impl Network {
+
+    //version byte prepended to Base58Check addresses
+    pub fn address_prefix(self: Self) -> u8 {
+        return match self {
+            Network::Mainnet => 0x00,
+            Network::Testnet => 0x6f,
+        };
+    }
+
+    pub fn from_address_prefix(prefix: u8) -> Option<Self> {
+        return match prefix {
+            0x00 => Some(Network::Mainnet),
+            0x6f => Some(Network::Testnet),
+            _ => None,
+        };
+    }
+
+    //sent in the handshake so nodes of different networks never
+    //exchange blocks
+    pub fn magic(self: Self) -> [u8; 4] {
+        return match self {
+            Network::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9],
+            Network::Testnet => [0x0b, 0x11, 0x09, 0x07],
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name {
+            "mainnet" => Some(Network::Mainnet),
+            "testnet" => Some(Network::Testnet),
+            _ => None,
+        };
+    }
+}
+
+//what a peer offers, as bit flags
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Services(pub u64);
+
+impl Services {
+    pub const NONE: Services = Services(0);
+    //serves every block of its chain
+    pub const FULL_BLOCKS: Services = Services(1);
+    //serves only recent blocks, the rest are pruned
+    pub const RECENT_BLOCKS: Services = Services(1 << 1);
+    //answers FetchTransaction and FetchHistory
+    pub const INDEX: Services = Services(1 << 2);
+
+    pub fn contains(self: Self, other: Services) -> bool {
+        return self.0 & other.0 == other.0;
+    }
+}
+
+impl BitOr for Services {
+    type Output = Services;
+
+    fn bitor(self, other: Services) -> Services {
+        return Services(self.0 | other.0);
+    }
+}
+
+//first message on every connection, from both sides
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Version {
+    pub protocol: u32,
+    pub magic: [u8; 4],
+    pub best_height: u64,
+    pub user_agent: String,
+    pub services: Services,
+}
+
+impl Version {
+
+    pub fn new(network: Network, best_height: u64, services: Services,
+               user_agent: &str) -> Self {
+        return Version {
+            protocol: PROTOCOL_VERSION,
+            magic: network.magic(),
+            best_height,
+            user_agent: user_agent.to_owned(),
+            services,
+        };
+    }
+
+    //a peer must be on our network and speak a protocol we know
+    pub fn check(self: &Self, peer: &Version) -> Result<(), BtcError> {
+        if peer.magic != self.magic {
+            return Err(BtcError::WrongNetwork);
+        }
+        if peer.protocol < MIN_PROTOCOL_VERSION {
+            return Err(BtcError::IncompatibleProtocol);
+        }
+        return Ok(());
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
+    /// Open a connection, telling the peer our protocol
+    /// version, network, best height and services
+    Version(Version),
+    /// Accept the peer's Version
+    Verack,
     /// Fetch all UTXOs belonging to a public key
     FetchUTXOs(PublicKey),
     /// UTXOs belonging to a public key. Bool determines if marked
@@ -43,10 +226,97 @@ pub enum Message {
     FetchBlock(usize),
     /// Broadcast a new block to other nodes
     NewBlock(Block),
+    /// Response to FetchBlock when the node does not have
+    /// that block, or pruned it
+    BlockNotAvailable(usize),
+    /// Ask a node for up to the given number of headers
+    /// starting at the specified height
+    FetchHeaders(usize, u32),
+    /// This is the response to FetchHeaders, at most
+    /// MAX_HEADERS_PER_MESSAGE in height order
+    Headers(Vec<BlockHeader>),
+    /// Ask a node where its chain parts from ours, given a
+    /// block locator: hashes back from our tip, exponentially
+    /// further apart, ending with the first block
+    LocateFork(Vec<Hash>),
+    /// This is the response to LocateFork. Height of the first
+    /// locator hash the node has, None if it has none, and the
+    /// hashes of up to MAX_HEADERS_PER_MESSAGE blocks after it
+    ForkPoint(Option<usize>, Vec<Hash>),
+    /// Ask a node for the fee per byte needed to be
+    /// confirmed within the given number of blocks
+    FetchFeeEstimate(u32),
+    /// This is the response to FetchFeeEstimate
+    FeeEstimate(u64),
+    /// Ask a node for a confirmed transaction by its hash
+    FetchTransaction(Hash),
+    /// This is the response to FetchTransaction, None if the
+    /// node does not know the transaction or pruned its block
+    Transaction(Option<(TxLocation, Transactions)>),
+    /// Ask a node for every confirmed output paid to a public
+    /// key, spent or not
+    FetchHistory(PublicKey),
+    /// This is the response to FetchHistory
+    History(Vec<OutputRecord>),
+    /// Response to FetchTransaction or FetchHistory when the
+    /// node does not keep the indexes
+    IndexNotAvailable,
 }
 
 impl Message {
 
+    //how many bytes a message of this type may take on the wire
+    pub fn size_class(self: &Self) -> SizeClass {
+        return match self {
+            Message::SubmitTransaction(_) | Message::NewTransaction(_)
+            | Message::Transaction(_) | Message::Template(_)
+            | Message::ValidateTemplate(_) | Message::SubmitTemplate(_)
+            | Message::NewBlock(_) => SizeClass::Block,
+            Message::Headers(_) | Message::LocateFork(_) | Message::ForkPoint(..) => {
+                SizeClass::Headers
+            }
+            Message::UTXOs(_) | Message::NodeList(_) | Message::History(_) => SizeClass::List,
+            _ => SizeClass::Small,
+        };
+    }
+
+    /* type byte sent in the frame header. A peer skips a frame of
+    a type it does not know, new variants get the next one and
+    MESSAGE_TYPES goes up with them */
+    pub fn message_type(self: &Self) -> u8 {
+        return match self {
+            Message::Version(_) => 0,
+            Message::Verack => 1,
+            Message::FetchUTXOs(_) => 2,
+            Message::UTXOs(_) => 3,
+            Message::SubmitTransaction(_) => 4,
+            Message::NewTransaction(_) => 5,
+            Message::FetchTemplate(_) => 6,
+            Message::Template(_) => 7,
+            Message::ValidateTemplate(_) => 8,
+            Message::TemplateValidity(_) => 9,
+            Message::SubmitTemplate(_) => 10,
+            Message::DiscoverNodes => 11,
+            Message::NodeList(_) => 12,
+            Message::AskDifference(_) => 13,
+            Message::Difference(_) => 14,
+            Message::FetchBlock(_) => 15,
+            Message::NewBlock(_) => 16,
+            Message::BlockNotAvailable(_) => 17,
+            Message::FetchHeaders(..) => 18,
+            Message::Headers(_) => 19,
+            Message::LocateFork(_) => 20,
+            Message::ForkPoint(..) => 21,
+            Message::FetchFeeEstimate(_) => 22,
+            Message::FeeEstimate(_) => 23,
+            Message::FetchTransaction(_) => 24,
+            Message::Transaction(_) => 25,
+            Message::FetchHistory(_) => 26,
+            Message::History(_) => 27,
+            Message::IndexNotAvailable => 28,
+        };
+    }
+
     pub fn encode(self: &Self) -> Result<Vec<u8>, ciborium::ser::Error<IOError>> {
         let mut bytes = Vec::new();
         ciborium::into_writer(self, &mut bytes)?;
@@ -57,21 +327,325 @@ impl Message {
         return ciborium::from_reader(data);
     }
 
-    pub fn send(self: &Self, stream: &mut impl Write) -> Result<(), ciborium::ser::Error<IOError>> {
+    //the message with its frame header in front
+    fn frame(self: &Self) -> Result<Vec<u8>, ciborium::ser::Error<IOError>> {
         let bytes = self.encode()?;
-        let len = bytes.len() as u64;
-        stream.write_all(&len.to_be_bytes())?;
-        stream.write_all(&bytes)?;
+        let mut framed = Vec::with_capacity(FRAME_HEADER_SIZE + bytes.len());
+        framed.push(self.size_class().to_byte());
+        framed.push(self.message_type());
+        framed.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+        framed.extend_from_slice(&bytes);
+        return Ok(framed);
+    }
+
+    pub fn send(self: &Self, stream: &mut impl Write) -> Result<(), ciborium::ser::Error<IOError>> {
+        stream.write_all(&self.frame()?)?;
         return Ok(());
     }
 
+    /* read one message behind its frame header. A length over
+    what the header's size class allows is refused without
+    reading on, the buffer only grows as bytes actually arrive,
+    and a message not of the type and class it was sent as is
+    refused once decoded. Frames of types we do not know are
+    skipped, up to MAX_UNKNOWN_FRAMES in a row. Errors other than
+    Io mean the peer sent something malformed */
     pub fn recieve(stream: &mut impl Read) -> Result<Self, ciborium::de::Error<IOError>> {
-        let mut len_bytes =  [0u8; 8];
-        stream.read_exact(&mut len_bytes)?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
-        let mut data = vec![0u8; len];
-        stream.read_exact(&mut data)?;
-        return Self::decode(&data);
+        for _ in 0..=MAX_UNKNOWN_FRAMES {
+            let mut header = [0u8; FRAME_HEADER_SIZE];
+            stream.read_exact(&mut header)?;
+            let (class, message_type, len) = check_header(header)?;
+            let mut data = Vec::new();
+            stream.take(len).read_to_end(&mut data)?;
+            if data.len() as u64 != len {
+                return Err(IOError::from(IOErrorKind::UnexpectedEof).into());
+            }
+            if let Some(frame) = Self::decode_framed(class, message_type, &data)? {
+                return Ok(frame);
+            }
+        }
+        return Err(too_many_unknown());
+    }
+
+    //send for async streams, same framing
+    pub async fn send_async(self: &Self, stream: &mut (impl AsyncWrite + Unpin))
+        -> Result<(), ciborium::ser::Error<IOError>> {
+        stream.write_all(&self.frame()?).await?;
+        stream.flush().await?;
+        return Ok(());
     }
+
+    //recieve for async streams, with the same limits
+    pub async fn recieve_async(stream: &mut (impl AsyncRead + Unpin))
+        -> Result<Self, ciborium::de::Error<IOError>> {
+        for _ in 0..=MAX_UNKNOWN_FRAMES {
+            let mut header = [0u8; FRAME_HEADER_SIZE];
+            stream.read_exact(&mut header).await?;
+            let (class, message_type, len) = check_header(header)?;
+            let mut data = Vec::new();
+            stream.take(len).read_to_end(&mut data).await?;
+            if data.len() as u64 != len {
+                return Err(IOError::from(IOErrorKind::UnexpectedEof).into());
+            }
+            if let Some(frame) = Self::decode_framed(class, message_type, &data)? {
+                return Ok(frame);
+            }
+        }
+        return Err(too_many_unknown());
+    }
+
+    /* decode the body of a frame, None if it is of a type we do not
+    know. One of a known type must decode as it, in its class */
+    fn decode_framed(class: SizeClass, message_type: u8, data: &[u8])
+        -> Result<Option<Self>, ciborium::de::Error<IOError>> {
+        if message_type >= MESSAGE_TYPES {
+            return Ok(None);
+        }
+        let message = Self::decode(data)?;
+        if message.message_type() != message_type {
+            return Err(wrong_type());
+        }
+        if message.size_class() != class {
+            return Err(wrong_class());
+        }
+        return Ok(Some(message));
+    }
+}
+
+//size class, type and length of the frame to follow, if the
+//length is within what the class allows
+fn check_header(header: [u8; FRAME_HEADER_SIZE])
+    -> Result<(SizeClass, u8, u64), ciborium::de::Error<IOError>> {
+    let class = SizeClass::from_byte(header[0]).ok_or_else(wrong_class)?;
+    let len = u64::from_be_bytes(header[2..].try_into().expect("BUG: Impossible"));
+    if len > class.max_size() {
+        return Err(too_large());
+    }
+    return Ok((class, header[1], len));
 }
 
+/* what one frame held: a message, or a type we do not know, as
+from a peer with variants newer than ours. The frame header gave
+its length so the connection goes on past it. Each comes with the
+bytes the frame took on the wire */
+#[derive(Debug, Clone)]
+pub enum Frame {
+    Message(Message, u64),
+    Unknown(u64),
+}
+
+/* the framing of send and recieve as a codec, to run many
+connections on one runtime with Framed instead of a thread
+each. Nothing is reserved for a frame ahead of its bytes. A frame
+of a type we do not know is handed on as Frame::Unknown, a frame
+header out of bounds or a body that is not a message of its
+type and class is an error which ends the connection */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessageCodec;
+
+impl Decoder for MessageCodec {
+    type Item = Frame;
+    type Error = ciborium::de::Error<IOError>;
+
+    fn decode(self: &mut Self, src: &mut BytesMut) -> Result<Option<Frame>, Self::Error> {
+        if src.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        let header: [u8; FRAME_HEADER_SIZE] = src[..FRAME_HEADER_SIZE].try_into().expect("BUG: Impossible");
+        let (class, message_type, len) = check_header(header)?;
+        if src.len() < FRAME_HEADER_SIZE + len as usize {
+            return Ok(None);
+        }
+        src.advance(FRAME_HEADER_SIZE);
+        let data = src.split_to(len as usize);
+        let wire_len = (FRAME_HEADER_SIZE as u64) + len;
+        return match Message::decode_framed(class, message_type, &data)? {
+            Some(message) => Ok(Some(Frame::Message(message, wire_len))),
+            None => Ok(Some(Frame::Unknown(wire_len))),
+        };
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = ciborium::ser::Error<IOError>;
+
+    fn encode(self: &mut Self, message: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.put_slice(&message.frame()?);
+        return Ok(());
+    }
+}
+
+fn too_large() -> ciborium::de::Error<IOError> {
+    return ciborium::de::Error::Semantic(None, BtcError::MessageTooLarge.to_string());
+}
+
+fn wrong_class() -> ciborium::de::Error<IOError> {
+    return ciborium::de::Error::Semantic(None, BtcError::MessageSizeClassMismatch.to_string());
+}
+
+fn wrong_type() -> ciborium::de::Error<IOError> {
+    return ciborium::de::Error::Semantic(None, BtcError::MessageTypeMismatch.to_string());
+}
+
+fn too_many_unknown() -> ciborium::de::Error<IOError> {
+    return ciborium::de::Error::Semantic(None, BtcError::TooManyUnknownMessages.to_string());
+}
+
+fn handshake_error(e: impl ToString) -> IOError {
+    return IOError::new(IOErrorKind::InvalidData, e.to_string());
+}
+
+fn expect_version(stream: &mut impl Read, ours: &Version) -> IOResult<Version> {
+    let Message::Version(theirs) = Message::recieve(stream).map_err(handshake_error)? else {
+        return Err(handshake_error(BtcError::HandshakeFailed));
+    };
+    ours.check(&theirs).map_err(handshake_error)?;
+    return Ok(theirs);
+}
+
+fn expect_verack(stream: &mut impl Read) -> IOResult<()> {
+    let Message::Verack = Message::recieve(stream).map_err(handshake_error)? else {
+        return Err(handshake_error(BtcError::HandshakeFailed));
+    };
+    return Ok(());
+}
+
+/* open a connection we made: send our Version, take the peer's
+and its Verack, then acknowledge it. Returns the peer's Version,
+a peer on another network or too old a protocol is refused */
+pub fn connect_handshake<S: Read + Write>(stream: &mut S, ours: &Version) -> IOResult<Version> {
+    Message::Version(ours.clone()).send(stream).map_err(handshake_error)?;
+    let theirs = expect_version(stream, ours)?;
+    expect_verack(stream)?;
+    Message::Verack.send(stream).map_err(handshake_error)?;
+    return Ok(theirs);
+}
+
+//the other side of connect_handshake, for connections we accept
+pub fn accept_handshake<S: Read + Write>(stream: &mut S, ours: &Version) -> IOResult<Version> {
+    let theirs = expect_version(stream, ours)?;
+    Message::Version(ours.clone()).send(stream).map_err(handshake_error)?;
+    Message::Verack.send(stream).map_err(handshake_error)?;
+    expect_verack(stream)?;
+    return Ok(theirs);
+}
+
+
+async fn expect_version_async<S>(framed: &mut Framed<S, MessageCodec>,
+                                 ours: &Version) -> IOResult<Version>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(Frame::Message(Message::Version(theirs), _)) = framed.next().await.transpose().map_err(handshake_error)? else {
+        return Err(handshake_error(BtcError::HandshakeFailed));
+    };
+    ours.check(&theirs).map_err(handshake_error)?;
+    return Ok(theirs);
+}
+
+async fn expect_verack_async<S>(framed: &mut Framed<S, MessageCodec>) -> IOResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(Frame::Message(Message::Verack, _)) = framed.next().await.transpose().map_err(handshake_error)? else {
+        return Err(handshake_error(BtcError::HandshakeFailed));
+    };
+    return Ok(());
+}
+
+//connect_handshake over a framed async connection
+pub async fn connect_handshake_async<S>(framed: &mut Framed<S, MessageCodec>,
+                                        ours: &Version) -> IOResult<Version>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    framed.send(Message::Version(ours.clone())).await.map_err(handshake_error)?;
+    let theirs = expect_version_async(framed, ours).await?;
+    expect_verack_async(framed).await?;
+    framed.send(Message::Verack).await.map_err(handshake_error)?;
+    return Ok(theirs);
+}
+
+//accept_handshake over a framed async connection
+pub async fn accept_handshake_async<S>(framed: &mut Framed<S, MessageCodec>,
+                                       ours: &Version) -> IOResult<Version>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let theirs = expect_version_async(framed, ours).await?;
+    framed.feed(Message::Version(ours.clone())).await.map_err(handshake_error)?;
+    framed.send(Message::Verack).await.map_err(handshake_error)?;
+    expect_verack_async(framed).await?;
+    return Ok(theirs);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //a frame with the given header fields around body
+    fn raw_frame(class: SizeClass, message_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut frame = vec![class.to_byte(), message_type];
+        frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+        frame.extend_from_slice(body);
+        return frame;
+    }
+
+    fn decode_all(bytes: &[u8]) -> Vec<Result<Frame, ciborium::de::Error<IOError>>> {
+        let mut src = BytesMut::from(bytes);
+        let mut frames = Vec::new();
+        loop {
+            match MessageCodec.decode(&mut src) {
+                Ok(Some(frame)) => frames.push(Ok(frame)),
+                Ok(None) => return frames,
+                Err(e) => {
+                    frames.push(Err(e));
+                    return frames;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_types_are_skipped() {
+        let unknown = raw_frame(SizeClass::Small, MESSAGE_TYPES, b"from a newer peer");
+        let known = Message::FeeEstimate(7).frame().unwrap();
+        let bytes = [unknown.clone(), known.clone()].concat();
+
+        let frames = decode_all(&bytes);
+        assert!(matches!(frames[0], Ok(Frame::Unknown(len)) if len == unknown.len() as u64));
+        assert!(matches!(frames[1], Ok(Frame::Message(Message::FeeEstimate(7), len))
+                         if len == known.len() as u64));
+        assert_eq!(frames.len(), 2);
+
+        let message = Message::recieve(&mut bytes.as_slice()).unwrap();
+        assert!(matches!(message, Message::FeeEstimate(7)));
+    }
+
+    #[test]
+    fn known_type_that_does_not_decode_is_malformed() {
+        let verack = Message::Verack.encode().unwrap();
+        let bad_frames = [
+            raw_frame(SizeClass::Small, Message::FeeEstimate(0).message_type(), b"garbage"),
+            raw_frame(SizeClass::Small, Message::DiscoverNodes.message_type(), &verack),
+            raw_frame(SizeClass::Block, Message::Verack.message_type(), &verack),
+        ];
+        for bytes in bad_frames {
+            let frames = decode_all(&bytes);
+            assert!(matches!(frames[..], [Err(_)]));
+            assert!(Message::recieve(&mut bytes.as_slice()).is_err());
+        }
+    }
+
+    #[test]
+    fn recieve_gives_up_after_too_many_unknown_frames() {
+        let unknown = raw_frame(SizeClass::Small, u8::MAX, &[]);
+        let mut bytes = unknown.repeat(MAX_UNKNOWN_FRAMES);
+        bytes.extend(Message::Verack.frame().unwrap());
+        assert!(matches!(Message::recieve(&mut bytes.as_slice()), Ok(Message::Verack)));
+
+        let mut bytes = unknown.repeat(MAX_UNKNOWN_FRAMES + 1);
+        bytes.extend(Message::Verack.frame().unwrap());
+        assert!(Message::recieve(&mut bytes.as_slice()).is_err());
+    }
+}
@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize};
 use std::fmt;
 
 #[derive(Clone, Copy, Serialize, Deserialize, 
-        Debug, PartialEq, Eq, Hash)]
+        Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Hash(U256);
 impl Hash {
 
@@ -41,6 +41,16 @@ impl Hash {
         let bytes = self.0.to_little_endian();
         return bytes.as_slice().try_into().unwrap();
     }
+
+    //inverse of as_bytes
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        return Hash(U256::from_little_endian(&bytes));
+    }
+
+    //inverse of Display
+    pub fn from_hex(s: &str) -> Option<Self> {
+        return U256::from_str_radix(s, 16).ok().map(Hash);
+    }
 }
 
 impl fmt::Display for Hash {
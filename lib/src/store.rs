@@ -0,0 +1,646 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read, Result as IOResult,
+    Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::BtcError;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, TransactionsOutput};
+use crate::util::{checksum, open_file, seal_file, write_atomic, FILE_CHECKSUM_SIZE};
+
+//layout of the files in a block store directory, kept in
+//VERSION_FILE. 2 is 1 with blocks known by their header hash
+pub const STORE_VERSION: u32 = 2;
+//file names inside a block store directory
+pub const VERSION_FILE: &str = "store.ver";
+pub const INDEX_FILE: &str = "blocks.idx";
+pub const HEADERS_FILE: &str = "headers.dat";
+pub const CHAINSTATE_FILE: &str = "chainstate.dat";
+pub const CHAINSTATE_JOURNAL_FILE: &str = "chainstate.log";
+pub const TXINDEX_FILE: &str = "txindex.dat";
+pub const TXINDEX_JOURNAL_FILE: &str = "txindex.log";
+//blocks and their undo data go in numbered files of about this
+//size, pruning deletes whole files
+pub const BLOCK_FILE_SIZE: u64 = 16 * 1024 * 1024;
+//hash, header offset and length, block file, offset and
+//length, undo length
+const INDEX_RECORD_SIZE: usize = 32 + 8 + 8 + 4 + 8 + 8 + 8;
+//a journal is folded into its saved value once it is larger than
+//the value, or than this while the value is small
+const MIN_COMPACT_SIZE: u64 = 1024 * 1024;
+//block file of an entry that only has a header, for the blocks
+//below a utxo snapshot
+const NO_BLOCK_FILE: u32 = u32::MAX;
+//length before each journal record
+const JOURNAL_LEN_SIZE: usize = 4;
+
+//all blocks went in this one file before there was a version
+const LEGACY_BLOCKS_FILE: &str = "blocks.dat";
+
+fn block_file_name(file: u32) -> String {
+    return format!("blk{:05}.dat", file);
+}
+
+fn unsupported_version() -> IOError {
+    return IOError::new(IOErrorKind::InvalidData, BtcError::UnsupportedStoreVersion);
+}
+
+/* version of the store in dir, 0 for one from before the
+version file. Stores that kept every block in one file and newer
+ones are refused */
+fn store_version(dir: &Path) -> IOResult<u32> {
+    let version_path = dir.join(VERSION_FILE);
+    if !version_path.exists() {
+        if dir.join(LEGACY_BLOCKS_FILE).exists() {
+            return Err(unsupported_version());
+        }
+        return Ok(0);
+    }
+    let bytes = fs::read(&version_path)?;
+    let version = <[u8; 4]>::try_from(bytes.as_slice()).ok().map(u32::from_be_bytes);
+    return match version {
+        Some(version) if version <= STORE_VERSION => Ok(version),
+        _ => Err(unsupported_version()),
+    };
+}
+
+//outputs a block spent, to put back when it is disconnected
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<(Hash, TransactionsOutput)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct IndexEntry {
+    hash: Hash,
+    header_offset: u64,
+    header_len: u64,
+    file: u32,
+    //the undo data follows the block in the same file
+    offset: u64,
+    len: u64,
+    undo_len: u64,
+}
+
+impl IndexEntry {
+
+    fn to_bytes(self: &Self) -> [u8; INDEX_RECORD_SIZE] {
+        let mut bytes = [0u8; INDEX_RECORD_SIZE];
+        bytes[..32].copy_from_slice(&self.hash.as_bytes());
+        bytes[32..40].copy_from_slice(&self.header_offset.to_be_bytes());
+        bytes[40..48].copy_from_slice(&self.header_len.to_be_bytes());
+        bytes[48..52].copy_from_slice(&self.file.to_be_bytes());
+        bytes[52..60].copy_from_slice(&self.offset.to_be_bytes());
+        bytes[60..68].copy_from_slice(&self.len.to_be_bytes());
+        bytes[68..].copy_from_slice(&self.undo_len.to_be_bytes());
+        return bytes;
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let u64_at = |start: usize| {
+            u64::from_be_bytes(bytes[start..start + 8].try_into().expect("BUG: Impossible"))
+        };
+        return IndexEntry {
+            hash: Hash::from_bytes(bytes[..32].try_into().expect("BUG: Impossible")),
+            header_offset: u64_at(32),
+            header_len: u64_at(40),
+            file: u32::from_be_bytes(bytes[48..52].try_into().expect("BUG: Impossible")),
+            offset: u64_at(52),
+            len: u64_at(60),
+            undo_len: u64_at(68),
+        };
+    }
+}
+
+fn read_at(path: &Path, offset: u64, len: u64) -> IOResult<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut bytes = vec![0u8; len as usize];
+    file.read_exact(&mut bytes)?;
+    return Ok(bytes);
+}
+
+fn append_synced(path: &Path, bytes: &[u8]) -> IOResult<u64> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let offset = file.metadata()?.len();
+    file.write_all(bytes)?;
+    file.sync_data()?;
+    return Ok(offset);
+}
+
+fn to_cbor<T: Serialize>(data: &T, name: &str) -> IOResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(data, &mut bytes).map_err(|_| {
+        IOError::new(IOErrorKind::InvalidData, format!("Failed to serialize {}", name))
+    })?;
+    return Ok(bytes);
+}
+
+fn from_cbor<T: for<'de> Deserialize<'de>>(bytes: &[u8], name: &str) -> IOResult<T> {
+    return ciborium::from_reader(bytes).map_err(|_| {
+        IOError::new(IOErrorKind::InvalidData, format!("Failed to deserialize {}", name))
+    });
+}
+
+/* append-only block files with an index from height and hash to
+where each block, its header and its undo data sit. Opening reads
+only the small fixed size index, the rest is read from disk when
+asked for. Data is written and synced before its index record, so
+a crash leaves at worst unindexed bytes at the end of a file.
+Headers are kept forever while pruning deletes old block files */
+#[derive(Clone, Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    index: Vec<IndexEntry>,
+    heights: HashMap<Hash, u64>,
+    //blocks below this height have been pruned
+    pruned: u64,
+}
+
+impl BlockStore {
+
+    pub fn open<P: AsRef<Path>>(dir: P) -> IOResult<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let version = store_version(&dir)?;
+        let index_path = dir.join(INDEX_FILE);
+        let mut bytes = Vec::new();
+        if index_path.exists() {
+            File::open(&index_path)?.read_to_end(&mut bytes)?;
+        }
+
+        //a torn record at the end is a write that never finished
+        let whole = bytes.len() - bytes.len() % INDEX_RECORD_SIZE;
+        if whole != bytes.len() {
+            OpenOptions::new().write(true).open(&index_path)?.set_len(whole as u64)?;
+        }
+
+        let mut store = BlockStore { dir, index: Vec::new(), heights: HashMap::new(), pruned: 0 };
+        for record in bytes[..whole].chunks(INDEX_RECORD_SIZE) {
+            let entry = IndexEntry::from_bytes(record);
+            store.heights.insert(entry.hash, store.index.len() as u64);
+            store.index.push(entry);
+        }
+
+        //files are pruned oldest first, blocks start at the first
+        //file still there
+        let mut file = None;
+        for entry in &store.index {
+            if file == Some(entry.file) || entry.file == NO_BLOCK_FILE {
+                store.pruned += 1;
+                continue;
+            }
+            if store.dir.join(block_file_name(entry.file)).exists() {
+                break;
+            }
+            file = Some(entry.file);
+            store.pruned += 1;
+        }
+        if version < STORE_VERSION {
+            store.upgrade(version)?;
+        }
+        return Ok(store);
+    }
+
+    /* bring a store from an older version up to STORE_VERSION.
+    Before 2 blocks were known by the hash of the whole block, the
+    blocks link to each other by it so those stores can not be
+    converted. Before 1 the chain state was in an older format, it
+    is removed to be rebuilt from the blocks, which have to be all
+    there for it */
+    fn upgrade(self: &Self, from: u32) -> IOResult<()> {
+        if let Some(entry) = self.index.first() {
+            let header = self.header(0)?.ok_or_else(unsupported_version)?;
+            if header.hash() != entry.hash {
+                return Err(unsupported_version());
+            }
+        }
+        if from == 0 && !self.is_empty() {
+            if self.pruned > 0 {
+                return Err(unsupported_version());
+            }
+            for name in [CHAINSTATE_FILE, CHAINSTATE_JOURNAL_FILE] {
+                if self.dir.join(name).exists() {
+                    fs::remove_file(self.dir.join(name))?;
+                }
+            }
+        }
+        return write_atomic(self.dir.join(VERSION_FILE), &STORE_VERSION.to_be_bytes());
+    }
+
+    pub fn dir(self: &Self) -> &Path {
+        return &self.dir;
+    }
+
+    //number of blocks stored, pruned ones included
+    pub fn len(self: &Self) -> u64 {
+        return self.index.len() as u64;
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        return self.index.is_empty();
+    }
+
+    //lowest height whose block is still on disk
+    pub fn pruned_height(self: &Self) -> u64 {
+        return self.pruned;
+    }
+
+    pub fn hash(self: &Self, height: u64) -> Option<Hash> {
+        return self.index.get(height as usize).map(|entry| entry.hash);
+    }
+
+    pub fn height(self: &Self, hash: &Hash) -> Option<u64> {
+        return self.heights.get(hash).copied();
+    }
+
+    pub fn last_hash(self: &Self) -> Option<Hash> {
+        return self.index.last().map(|entry| entry.hash);
+    }
+
+    //write a block and the outputs it spent at the next height
+    pub fn append(self: &mut Self, block: &Block, undo: &BlockUndo) -> IOResult<u64> {
+        let header = to_cbor(&block.header, "BlockHeader")?;
+        let mut bytes = to_cbor(block, "Block")?;
+        let len = bytes.len() as u64;
+        bytes.extend(to_cbor(undo, "BlockUndo")?);
+
+        //start a new file once the last one is full
+        let mut file = self.index.iter().rev()
+                        .map(|entry| entry.file)
+                        .find(|file| *file != NO_BLOCK_FILE)
+                        .unwrap_or(0);
+        let path = self.dir.join(block_file_name(file));
+        if path.exists() && fs::metadata(&path)?.len() >= BLOCK_FILE_SIZE {
+            file += 1;
+        }
+        let offset = append_synced(&self.dir.join(block_file_name(file)), &bytes)?;
+        let header_offset = append_synced(&self.dir.join(HEADERS_FILE), &header)?;
+
+        let entry = IndexEntry {
+            hash: block.hash(),
+            header_offset,
+            header_len: header.len() as u64,
+            file,
+            offset,
+            len,
+            undo_len: bytes.len() as u64 - len,
+        };
+        append_synced(&self.dir.join(INDEX_FILE), &entry.to_bytes())?;
+
+        let height = self.len();
+        self.heights.insert(entry.hash, height);
+        self.index.push(entry);
+        return Ok(height);
+    }
+
+    /* write only the header for the next height, its block counts
+    as pruned. For the blocks below a utxo snapshot, which are
+    never downloaded */
+    pub fn append_header(self: &mut Self, header: &BlockHeader) -> IOResult<u64> {
+        if self.pruned != self.len() {
+            return Err(IOError::new(IOErrorKind::InvalidInput,
+                "Headers without blocks can only follow others"));
+        }
+        let bytes = to_cbor(header, "BlockHeader")?;
+        let header_offset = append_synced(&self.dir.join(HEADERS_FILE), &bytes)?;
+        let entry = IndexEntry {
+            hash: header.hash(),
+            header_offset,
+            header_len: bytes.len() as u64,
+            file: NO_BLOCK_FILE,
+            offset: 0,
+            len: 0,
+            undo_len: 0,
+        };
+        append_synced(&self.dir.join(INDEX_FILE), &entry.to_bytes())?;
+
+        let height = self.len();
+        self.heights.insert(entry.hash, height);
+        self.index.push(entry);
+        self.pruned += 1;
+        return Ok(height);
+    }
+
+    pub fn header(self: &Self, height: u64) -> IOResult<Option<BlockHeader>> {
+        let Some(entry) = self.index.get(height as usize) else {
+            return Ok(None);
+        };
+        let bytes = read_at(&self.dir.join(HEADERS_FILE), entry.header_offset, entry.header_len)?;
+        return Ok(Some(from_cbor(&bytes, "BlockHeader")?));
+    }
+
+    //None if there is no such block or it was pruned
+    pub fn block(self: &Self, height: u64) -> IOResult<Option<Block>> {
+        if height < self.pruned {
+            return Ok(None);
+        }
+        let Some(entry) = self.index.get(height as usize) else {
+            return Ok(None);
+        };
+        let path = self.dir.join(block_file_name(entry.file));
+        let bytes = read_at(&path, entry.offset, entry.len)?;
+        return Ok(Some(from_cbor(&bytes, "Block")?));
+    }
+
+    pub fn block_by_hash(self: &Self, hash: &Hash) -> IOResult<Option<Block>> {
+        return match self.height(hash) {
+            Some(height) => self.block(height),
+            None => Ok(None),
+        };
+    }
+
+    //None if there is no such block or it was pruned
+    pub fn undo(self: &Self, height: u64) -> IOResult<Option<BlockUndo>> {
+        if height < self.pruned {
+            return Ok(None);
+        }
+        let Some(entry) = self.index.get(height as usize) else {
+            return Ok(None);
+        };
+        let path = self.dir.join(block_file_name(entry.file));
+        let bytes = read_at(&path, entry.offset + entry.len, entry.undo_len)?;
+        return Ok(Some(from_cbor(&bytes, "BlockUndo")?));
+    }
+
+    /* forget the blocks from height len on, when they are
+    disconnected. Their bytes stay in the block files unindexed */
+    pub fn truncate(self: &mut Self, len: u64) -> IOResult<()> {
+        if len >= self.len() {
+            return Ok(());
+        }
+        let index = OpenOptions::new().write(true).open(self.dir.join(INDEX_FILE))?;
+        index.set_len(len * INDEX_RECORD_SIZE as u64)?;
+        index.sync_data()?;
+        for entry in self.index.drain(len as usize..) {
+            self.heights.remove(&entry.hash);
+        }
+        self.pruned = self.pruned.min(len);
+        return Ok(());
+    }
+
+    /* delete the oldest block files while every block in them is
+    older than the last keep blocks. The file being written to is
+    never deleted */
+    pub fn prune(self: &mut Self, keep: u64) -> IOResult<()> {
+        let keep_from = self.len().saturating_sub(keep);
+        while self.pruned < keep_from {
+            let file = self.index[self.pruned as usize].file;
+            let end = self.index[self.pruned as usize..].iter()
+                        .position(|entry| entry.file != file)
+                        .map(|idx| self.pruned + idx as u64);
+            let Some(end) = end.filter(|end| *end <= keep_from) else {
+                break;
+            };
+            fs::remove_file(self.dir.join(block_file_name(file)))?;
+            self.pruned = end;
+        }
+        return Ok(());
+    }
+}
+
+/* a value saved whole now and then, with each change made to it
+since appended to a journal next to it. Keeping it on disk costs
+the size of the change rather than of the whole value. Every save
+starts a new generation, changes of an older one left in the
+journal by a crash during a save are skipped when reading it */
+#[derive(Clone, Debug)]
+pub struct Journaled {
+    path: PathBuf,
+    journal_path: PathBuf,
+    generation: u64,
+    saved_len: u64,
+    journal_len: u64,
+    //a change failed to append, nothing more goes in the journal
+    //until the value is saved again
+    stale: bool,
+}
+
+impl Journaled {
+
+    /* open the value at path and its journal, None if it was never
+    saved. The changes come in the order they were made, a torn
+    one at the end of the journal is cut off */
+    pub fn open<T, C>(path: PathBuf, journal_path: PathBuf) -> IOResult<(Self, Option<T>, Vec<C>)>
+    where
+        T: for<'de> Deserialize<'de>,
+        C: for<'de> Deserialize<'de>,
+    {
+        let mut journaled = Journaled {
+            path,
+            journal_path,
+            generation: 0,
+            saved_len: 0,
+            journal_len: 0,
+            stale: false,
+        };
+        let mut value = None;
+        if journaled.path.exists() {
+            let bytes = fs::read(&journaled.path)?;
+            let (generation, saved): (u64, T) = from_cbor(open_file(&bytes)?, "saved value")?;
+            journaled.generation = generation;
+            journaled.saved_len = bytes.len() as u64;
+            value = Some(saved);
+        }
+
+        let mut bytes = Vec::new();
+        if journaled.journal_path.exists() {
+            File::open(&journaled.journal_path)?.read_to_end(&mut bytes)?;
+        }
+        let mut changes = Vec::new();
+        let mut pos = 0;
+        while let Some(payload) = journal_record(&bytes[pos..]) {
+            let (generation, change): (u64, C) = from_cbor(payload, "journal record")?;
+            if generation == journaled.generation {
+                changes.push(change);
+            }
+            pos += JOURNAL_LEN_SIZE + payload.len() + FILE_CHECKSUM_SIZE;
+        }
+        if pos != bytes.len() {
+            OpenOptions::new().write(true).open(&journaled.journal_path)?.set_len(pos as u64)?;
+        }
+        journaled.journal_len = pos as u64;
+        return Ok((journaled, value, changes));
+    }
+
+    //add a change made after the value was last saved
+    pub fn append<C: Serialize>(self: &mut Self, change: &C) -> IOResult<()> {
+        if self.stale {
+            return Err(IOError::other("Journal is missing changes until the next save"));
+        }
+        let payload = to_cbor(&(self.generation, change), "journal record")?;
+        let mut bytes = Vec::with_capacity(JOURNAL_LEN_SIZE + payload.len() + FILE_CHECKSUM_SIZE);
+        bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes.extend_from_slice(&checksum(&payload));
+
+        self.stale = true;
+        append_synced(&self.journal_path, &bytes)?;
+        self.stale = false;
+        self.journal_len += bytes.len() as u64;
+        return Ok(());
+    }
+
+    //whether the journal has grown enough to fold it into the value
+    pub fn needs_save(self: &Self) -> bool {
+        return self.stale || self.journal_len > self.saved_len.max(MIN_COMPACT_SIZE);
+    }
+
+    //save the whole value, the journal starts over empty
+    pub fn save<T: Serialize>(self: &mut Self, value: &T) -> IOResult<()> {
+        let generation = self.generation + 1;
+        let bytes = seal_file(&to_cbor(&(generation, value), "saved value")?);
+        write_atomic(&self.path, &bytes)?;
+        self.generation = generation;
+        self.saved_len = bytes.len() as u64;
+
+        let journal = File::create(&self.journal_path)?;
+        journal.sync_all()?;
+        self.journal_len = 0;
+        self.stale = false;
+        return Ok(());
+    }
+}
+
+//payload of the journal record bytes start with, None if it is
+//torn or damaged
+fn journal_record(bytes: &[u8]) -> Option<&[u8]> {
+    let len = u32::from_be_bytes(bytes.get(..JOURNAL_LEN_SIZE)?.try_into().ok()?) as usize;
+    let payload = bytes.get(JOURNAL_LEN_SIZE..JOURNAL_LEN_SIZE + len)?;
+    let sum = bytes.get(JOURNAL_LEN_SIZE + len..JOURNAL_LEN_SIZE + len + FILE_CHECKSUM_SIZE)?;
+    if sum != checksum(payload) {
+        return None;
+    }
+    return Some(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::crypto::PubKeyHash;
+    use crate::types::{BlockHeader, OutputLock, Transactions, TransactionsOutput};
+    use crate::util::{MerkleRoot, TestDir};
+
+    //a block after prev with outputs outputs, not mined
+    fn block(prev: Hash, outputs: usize) -> Block {
+        let outputs = (0..outputs).map(|_| TransactionsOutput {
+            value: 1,
+            unique_id: Uuid::new_v4(),
+            lock: OutputLock::PubKeyHash(PubKeyHash([0; 20])),
+        }).collect();
+        let transactions = vec![Transactions::new(vec![], outputs)];
+        let header = BlockHeader::new(Utc::now(), 0, prev, MerkleRoot::calculate(&transactions),
+                                      crate::MIN_TARGET);
+        return Block::new(header, transactions);
+    }
+
+    fn append_blocks(store: &mut BlockStore, count: usize, outputs: usize) -> Vec<Hash> {
+        let mut hashes = Vec::new();
+        for _ in 0..count {
+            let prev = store.last_hash().unwrap_or(Hash::zero());
+            let block = block(prev, outputs);
+            store.append(&block, &BlockUndo { spent: vec![] }).unwrap();
+            hashes.push(block.hash());
+        }
+        return hashes;
+    }
+
+    #[test]
+    fn append_and_reopen() {
+        let dir = TestDir::new("store-append");
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        let hashes = append_blocks(&mut store, 5, 1);
+
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.last_hash(), Some(hashes[4]));
+        assert_eq!(store.height(&hashes[2]), Some(2));
+        assert_eq!(store.block(3).unwrap().unwrap().hash(), hashes[3]);
+        assert_eq!(store.block_by_hash(&hashes[1]).unwrap().unwrap().hash(), hashes[1]);
+        assert!(store.block(5).unwrap().is_none());
+    }
+
+    #[test]
+    fn torn_index_record_is_dropped() {
+        let dir = TestDir::new("store-torn");
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        let hashes = append_blocks(&mut store, 3, 1);
+
+        //half a record, as left by a crash while appending
+        let mut index = OpenOptions::new().append(true).open(dir.path().join(INDEX_FILE)).unwrap();
+        index.write_all(&[0xff; INDEX_RECORD_SIZE / 2]).unwrap();
+        drop(index);
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.last_hash(), Some(hashes[2]));
+        let added = append_blocks(&mut store, 1, 1);
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.block(3).unwrap().unwrap().hash(), added[0]);
+    }
+
+    #[test]
+    fn truncate_and_reopen() {
+        let dir = TestDir::new("store-truncate");
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        let hashes = append_blocks(&mut store, 5, 1);
+
+        store.truncate(3).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.last_hash(), Some(hashes[2]));
+        assert_eq!(store.height(&hashes[4]), None);
+        assert!(store.block(3).unwrap().is_none());
+
+        //new blocks go after the unindexed bytes left behind
+        let replaced = append_blocks(&mut store, 2, 1);
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.hash(2), Some(hashes[2]));
+        assert_eq!(store.height(&replaced[1]), Some(4));
+        assert_eq!(store.block(4).unwrap().unwrap().hash(), replaced[1]);
+        assert!(store.undo(4).unwrap().unwrap().spent.is_empty());
+    }
+
+    #[test]
+    fn prune_and_reopen() {
+        let dir = TestDir::new("store-prune");
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        //blocks of a few MiB each, so they fill more than one file
+        let hashes = append_blocks(&mut store, 12, 40_000);
+        let first_file_len = store.index.iter().filter(|entry| entry.file == 0).count() as u64;
+        assert!(first_file_len < 12);
+
+        //nothing goes while the newest blocks are still in the first file
+        store.prune(12).unwrap();
+        assert_eq!(store.pruned_height(), 0);
+
+        store.prune(1).unwrap();
+        assert_eq!(store.pruned_height(), first_file_len);
+        assert!(!dir.path().join(block_file_name(0)).exists());
+        assert!(store.block(0).unwrap().is_none());
+        assert!(store.undo(0).unwrap().is_none());
+        assert_eq!(store.header(1).unwrap().unwrap().prev_block_hash, hashes[0]);
+        assert_eq!(store.block(11).unwrap().unwrap().hash(), hashes[11]);
+
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 12);
+        assert_eq!(store.pruned_height(), first_file_len);
+        assert!(store.block(0).unwrap().is_none());
+        assert_eq!(store.header(1).unwrap().unwrap().prev_block_hash, hashes[0]);
+        assert_eq!(store.block(first_file_len).unwrap().unwrap().hash(), hashes[first_file_len as usize]);
+    }
+
+    #[test]
+    fn unknown_version_is_refused() {
+        let dir = TestDir::new("store-version");
+        BlockStore::open(dir.path()).unwrap();
+        write_atomic(dir.path().join(VERSION_FILE), &(STORE_VERSION + 1).to_be_bytes()).unwrap();
+        assert!(BlockStore::open(dir.path()).is_err());
+    }
+}
@@ -549,8 +549,18 @@ impl BlockChain {
 
 mod block;
 mod blockchain;
+mod index;
+mod mempool;
 mod transaction;
+mod psbt;
+mod snapshot;
 
 pub use block::{Block, BlockHeader};
 pub use blockchain::BlockChain;
-pub use transaction::{Transactions, TransactionsInput, TransactionsOutput};
\ No newline at end of file
+pub use index::{ChainIndex, OutputRecord, TxLocation};
+pub use mempool::{FeeRate, Mempool, MempoolEntry, SavedMempool};
+pub use psbt::PartiallySignedTransaction;
+pub use snapshot::UtxoSnapshot;
+pub use transaction::{
+    OutputLock, Transactions, TransactionsInput, TransactionsOutput, UnsignedTransaction,
+};
\ No newline at end of file
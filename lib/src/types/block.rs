@@ -1,15 +1,17 @@
 use crate::U256;
+use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use crate::util::MerkleRoot;
 use crate::sha256::Hash;
+use crate::crypto::{SchnorrBatch, Signature};
 use crate::error::{BtcError, Result};
 use std::collections::HashMap;
 use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read,
     Result as IOResult, Write};
 
 use super::{Transactions, TransactionsOutput};
-use crate::util::Saveable;
+use crate::util::{serialized_size, Saveable};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BlockHeader {
@@ -37,6 +39,107 @@ impl BlockHeader {
         return Hash::hash(self);
     }
 
+    //expected number of hashes to find a block at this target
+    pub fn work(self: &Self) -> U256 {
+        return U256::MAX / self.target.saturating_add(U256::one());
+    }
+
+    /* check the headers of the blocks from height start on, with
+    earlier the headers right before them: the last
+    DIFFICULTY_UPDATE_INETRVAL, or all if there are fewer. Each
+    links to the one before it, is later than it and is mined at
+    the target the chain has at its height, never above MIN_TARGET.
+    Apart from a genesis it meets that target. Bodies are checked
+    when the blocks are added */
+    pub fn check_chain(start: u64, earlier: &[BlockHeader], headers: &[BlockHeader]) -> Result<()> {
+        let first = start.checked_sub(earlier.len() as u64).ok_or(BtcError::InvalidBlockHeader)?;
+        let header_at = |height: u64| {
+            let idx = height.checked_sub(first)? as usize;
+            return earlier.iter().chain(headers).nth(idx).cloned();
+        };
+        for (height, header) in (start..).zip(headers) {
+            if header.target > crate::MIN_TARGET {
+                return Err(BtcError::InvalidBlockHeader);
+            }
+            if height == 0 {
+                if header.prev_block_hash != Hash::zero() {
+                    return Err(BtcError::InvalidBlockHeader);
+                }
+                continue;
+            }
+            let prev = header_at(height - 1).ok_or(BtcError::InvalidBlockHeader)?;
+            let target = Self::expected_target(height, header_at)
+                            .ok_or(BtcError::InvalidBlockHeader)?;
+            if header.prev_block_hash != prev.hash()
+                || header.timestamp <= prev.timestamp
+                || header.target != target
+                || !header.hash().matches_target(header.target) {
+                return Err(BtcError::InvalidBlockHeader);
+            }
+        }
+        return Ok(());
+    }
+
+    /* target the block at height is mined at, given the headers
+    before it: the one of the block before, adjusted every
+    DIFFICULTY_UPDATE_INETRVAL blocks. None if a header it needs
+    is missing */
+    pub fn expected_target(height: u64, header_at: impl Fn(u64) -> Option<BlockHeader>)
+        -> Option<U256> {
+        let target = match height {
+            0 | 1 => crate::MIN_TARGET,
+            height => header_at(height - 1)?.target,
+        };
+        if height == 0 || !height.is_multiple_of(crate::DIFFICULTY_UPDATE_INETRVAL) {
+            return Some(target);
+        }
+        let first = header_at(height - crate::DIFFICULTY_UPDATE_INETRVAL)?;
+        let last = header_at(height - 1)?;
+        return Some(Self::retarget(target, &first, &last));
+    }
+
+    //target after first to last took to mine at target
+    pub fn retarget(target: U256, first: &BlockHeader, last: &BlockHeader) -> U256 {
+        let start_time = first.timestamp;
+        let end_time = last.timestamp;
+        let time_diff = (end_time - start_time).num_seconds();
+        let target_seconds = crate::IDEAL_BLOCK_TIME
+                        * crate::DIFFICULTY_UPDATE_INETRVAL;
+        //multiply the current target by actual time divided by
+        //ideal time
+
+        /*let new_target = target
+            * (time_diff as f64 / target_seconds as f64) as usize; */
+        
+        let new_target = BigDecimal::parse_bytes(
+            target.to_string().as_bytes(), 10)
+            .expect("BUG: impossible")
+            *(BigDecimal::from(time_diff)
+               / BigDecimal::from(target_seconds));
+        
+        let new_target_str = new_target.to_string()
+                                        .split(".")
+                                        .next()
+                                        .expect("BUG: Expected a decimal point")
+                                        .to_owned();
+        
+        let new_target = U256::from_str_radix(&new_target_str, 10)
+                                                            .expect("BUG: Impossible");
+
+        //4 * target > new_target > target / 4
+        let new_target = if new_target < target / 4 {
+            target / 4
+        }else if new_target > target * 4 {
+            target * 4
+        } else {
+            new_target
+        };
+
+        //if new_target > minimum target
+        //set it to the minmum target
+        return new_target.min(crate::MIN_TARGET);
+    }
+
     pub fn mine(self: &mut Self, steps: usize) -> bool {
         //if block already matches target
         if self.hash().matches_target(self.target) {
@@ -72,13 +175,27 @@ impl Block {
     ) -> Self {
 
         return Block {
-            header: header,
-            transactions: transactions
+            header,
+            transactions
         };
     }
 
+    //the header commits to the transactions through the merkle
+    //root, so its hash stands for the whole block
     pub fn hash(self: &Self) -> Hash {
-        return Hash::hash(self);
+        return self.header.hash();
+    }
+
+    //serialized size in bytes, bounded by MAX_BLOCK_SIZE
+    pub fn size(self: &Self) -> usize {
+        return serialized_size(self);
+    }
+
+    //coinbase reward before fees for a block at this height
+    pub fn calculate_block_reward(block_height: u64) -> u64 {
+        return crate::INITIAL_REWARD
+                * 10u64.pow(8)
+                / 2u64.pow((block_height / crate::HALVING_INTERVAL) as u32);
     }
 
     pub fn verify_transaction(self: &Self, 
@@ -86,6 +203,8 @@ impl Block {
                             utxos: &HashMap<Hash, 
                             (bool, TransactionsOutput)>) -> Result<()> {
         let mut inputs: HashMap<Hash, TransactionsOutput> = HashMap::new();
+        let mut created: HashMap<Hash, TransactionsOutput> = HashMap::new();
+        let mut schnorr_batch = SchnorrBatch::new();
         //reject empty blocks
         if self.transactions.is_empty() {
             return Err(BtcError::InvalidTransaction);
@@ -97,9 +216,9 @@ impl Block {
             let mut input_value = 0;
             let mut output_value = 0;
             for input in &transaction.inputs {
-                let prev_output = utxos.get(
-                    &input.prev_transaction_output_hash,
-                ).map(|(_, output)| output);
+                let prev_output = find_prev_output(
+                    utxos, &created, &input.prev_transaction_output_hash,
+                );
                 if prev_output.is_none() {
                     return Err(BtcError::InvalidTransaction);
                 }
@@ -108,8 +227,17 @@ impl Block {
                 if inputs.contains_key(&input.prev_transaction_output_hash) {
                     return Err(BtcError::InvalidTransaction);
                 }
-                //signature validation
-                if !input.signature.verify(&input.prev_transaction_output_hash, &prev_output.pubkey) {
+                //signature validation against the locking key
+                let pubkey = prev_output.lock.spending_key(input.pubkey.as_ref());
+                if pubkey.is_none() {
+                    return Err(BtcError::InvalidTransaction);
+                }
+                //schnorr signatures are checked together after the loop
+                if let Signature::Schnorr(signature) = &input.signature {
+                    schnorr_batch.push(input.prev_transaction_output_hash,
+                                       *signature,
+                                       pubkey.unwrap().x_only());
+                } else if !input.signature.verify(&input.prev_transaction_output_hash, pubkey.unwrap()) {
                     return Err(BtcError::InvalidTransaction);
                 }
 
@@ -119,6 +247,7 @@ impl Block {
             
             for output in &transaction.outputs {
                 output_value += output.value;
+                created.insert(output.hash(), output.clone());
             }
 
             //output_value less than input_value is the fee for the miner
@@ -127,6 +256,11 @@ impl Block {
             }
         }
 
+        //batch verify all schnorr signatures in the block
+        if !schnorr_batch.is_empty() && !schnorr_batch.verify() {
+            return Err(BtcError::InvalidSignature);
+        }
+
         return Ok(());
     }
 
@@ -136,21 +270,16 @@ impl Block {
     ) -> Result<()> {
         //coinbase tx is the first tx in the block
         let coinbase_transaction = &self.transactions[0];
-        if coinbase_transaction.inputs.len() != 0 {
+        if !coinbase_transaction.inputs.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
-        if coinbase_transaction.outputs.len() == 0 {
+        if coinbase_transaction.outputs.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
 
         //yet to implement function to calculate minor fees
         let miner_fees = self.calculate_miner_fees(utxos)?;
-        let block_reward = crate::INITIAL_REWARD 
-                                * 10u64.pow(8)
-                                / 2u64.pow(
-                                    (predicted_block_height
-                                    / crate::HALVING_INTERVAL) as u32
-                                );
+        let block_reward = Self::calculate_block_reward(predicted_block_height);
         let total_coinbase_outputs: u64 = 
                                 coinbase_transaction
                                 .outputs
@@ -164,6 +293,34 @@ impl Block {
         return Ok(());
     }
 
+    //fee per byte paid by every transaction after coinbase
+    pub fn fee_rates(
+        self: &Self,
+        utxos: &HashMap<Hash, (bool, TransactionsOutput)>
+    ) -> Result<Vec<u64>> {
+        let mut fee_rates = Vec::new();
+        let mut created: HashMap<Hash, TransactionsOutput> = HashMap::new();
+        for transaction in self.transactions.iter().skip(1) {
+            let mut input_value = 0;
+            for input in &transaction.inputs {
+                let prev_output = find_prev_output(utxos, &created,
+                                                   &input.prev_transaction_output_hash)
+                                    .ok_or(BtcError::InvalidTransaction)?;
+                input_value += prev_output.value;
+            }
+            for output in &transaction.outputs {
+                created.insert(output.hash(), output.clone());
+            }
+            let output_value: u64 = transaction.outputs.iter()
+                                        .map(|output| output.value)
+                                        .sum();
+            let fee = input_value.checked_sub(output_value)
+                        .ok_or(BtcError::InvalidTransaction)?;
+            fee_rates.push(fee / transaction.size() as u64);
+        }
+        return Ok(fee_rates);
+    }
+
     pub fn calculate_miner_fees(
         self: &Self,
         utxos: &HashMap<Hash, (bool, TransactionsOutput)>
@@ -177,9 +334,9 @@ impl Block {
                 the values of the outputs 
                 so we need to match inputs
                 to outptuts*/
-                let prev_output = utxos.get(
-                    &input.prev_transaction_output_hash
-                ).map(|(_, output)| output);
+                let prev_output = find_prev_output(
+                    utxos, &outputs, &input.prev_transaction_output_hash
+                );
                 if prev_output.is_none() {
                     return Err(BtcError::InvalidTransaction);
                 }
@@ -214,6 +371,18 @@ impl Block {
     }
 }
 
+/*output spent by an input, either unspent before the block or
+created by an earlier transaction in the same block*/
+fn find_prev_output<'a>(
+    utxos: &'a HashMap<Hash, (bool, TransactionsOutput)>,
+    created: &'a HashMap<Hash, TransactionsOutput>,
+    hash: &Hash,
+) -> Option<&'a TransactionsOutput> {
+    return utxos.get(hash)
+                .map(|(_, output)| output)
+                .or_else(|| created.get(hash));
+}
+
 impl Saveable for Block {
 
     fn load<I: Read>(reader: I) -> IOResult<Self> {
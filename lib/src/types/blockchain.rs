@@ -1,18 +1,24 @@
 use crate::U256;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::crypto::PublicKey;
 use crate::util::MerkleRoot;
 use crate::sha256::Hash;
 use crate::error::{BtcError, Result};
-use std::collections::{HashMap, HashSet};
-use bigdecimal::BigDecimal;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read,
     Result as IOResult, Write};
+use std::path::Path;
 
 
-use super::{Transactions, TransactionsOutput};
-use super::Block;
+use super::{OutputLock, Transactions, TransactionsOutput};
+use super::{Block, BlockHeader, ChainIndex, Mempool, OutputRecord, SavedMempool,
+    TxLocation, UtxoSnapshot};
 use crate::util::Saveable;
+use crate::store::{BlockStore, BlockUndo, Journaled, CHAINSTATE_FILE,
+    CHAINSTATE_JOURNAL_FILE, TXINDEX_FILE, TXINDEX_JOURNAL_FILE};
+use super::index::{IndexDelta, IndexedTransaction, SavedIndex};
 
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -20,8 +26,42 @@ pub struct BlockChain {
     utxos: HashMap<Hash, (bool, TransactionsOutput)>,
     target: U256,
     blocks: Vec<Block>,
-    #[serde(default, skip_serializing)]
-    mempool: Vec<(DateTime<Utc>, Transactions)>
+    //outputs spent by the last undo.len() of blocks, files from
+    //before it was kept have none for their older blocks
+    #[serde(default)]
+    undo: Vec<BlockUndo>,
+    //when opened from a directory the blocks are kept on disk
+    //here instead of in blocks
+    #[serde(skip)]
+    store: Option<BlockStore>,
+    //utxo set next to the store, with what each block changed
+    #[serde(skip)]
+    chainstate: Option<Journaled>,
+    //height and last block hash of a snapshot opened in a store
+    //that still lacks the headers up to it
+    #[serde(skip)]
+    pending_snapshot: Option<(u64, Hash)>,
+    //full blocks to keep in the store when pruning
+    #[serde(skip)]
+    prune: Option<u64>,
+    #[serde(skip)]
+    mempool: Mempool,
+    //median fee rate of each of the last FEE_HISTORY_BLOCKS blocks
+    #[serde(default)]
+    fee_history: VecDeque<u64>,
+    //txid and pubkey lookups, only kept when enabled
+    #[serde(skip)]
+    index: Option<ChainIndex>,
+    //the index next to the store, with each block it took in
+    #[serde(skip)]
+    index_journal: Option<Journaled>,
+}
+
+impl Default for BlockChain {
+
+    fn default() -> Self {
+        return Self::new();
+    }
 }
 
 impl BlockChain {
@@ -30,7 +70,15 @@ impl BlockChain {
         return BlockChain{utxos: HashMap::new(),
                           target: crate::MIN_TARGET,
                           blocks: Vec::new(),
-                          mempool: Vec::new()};
+                          undo: Vec::new(),
+                          store: None,
+                          chainstate: None,
+                          pending_snapshot: None,
+                          prune: None,
+                          mempool: Mempool::new(),
+                          fee_history: VecDeque::new(),
+                          index: None,
+                          index_journal: None};
     }
 
     pub fn utxos(self: &Self) -> &HashMap<Hash, (bool, TransactionsOutput)> {
@@ -41,22 +89,533 @@ impl BlockChain {
         return self.target;
     }
 
-    pub fn blocks(self: &Self) -> impl Iterator<Item = &Block> {
-        return self.blocks.iter();
+    /* open the chain kept in dir, creating it if missing. Only the
+    block index and the chain state with the changes journaled
+    since it was saved are read, blocks stored after the last of
+    them are replayed from the store */
+    pub fn open<P: AsRef<Path>>(dir: P) -> IOResult<Self> {
+        let store = BlockStore::open(dir)?;
+        let (chainstate, state, changes) = Self::open_chainstate(&store)?;
+        return Self::open_at(store, chainstate, state.unwrap_or_default(), changes);
+    }
+
+    fn open_chainstate(store: &BlockStore)
+        -> IOResult<(Journaled, Option<ChainState>, Vec<ChainDelta>)> {
+        return Journaled::open(store.dir().join(CHAINSTATE_FILE),
+                               store.dir().join(CHAINSTATE_JOURNAL_FILE));
+    }
+
+    /* open the chain kept in dir starting from the utxo set in
+    snapshot instead of the saved chain state. The blocks up to
+    the snapshot are trusted until verify_snapshot has checked it.
+    An empty store takes the snapshot as it is, with the headers up
+    to it to come from add_headers and the blocks after it synced
+    as usual */
+    pub fn open_snapshot<P: AsRef<Path>>(dir: P, snapshot: UtxoSnapshot) -> IOResult<Self> {
+        let store = BlockStore::open(dir)?;
+        let last_hash = match snapshot.height {
+            0 => Some(Hash::zero()),
+            height => store.hash(height - 1),
+        };
+        if !store.is_empty() && last_hash != Some(snapshot.block_hash) {
+            return Err(IOError::new(IOErrorKind::InvalidData, BtcError::InvalidSnapshot));
+        }
+        snapshot.verify_entries().map_err(|e| IOError::new(IOErrorKind::InvalidData, e))?;
+
+        let state = ChainState {
+            height: snapshot.height,
+            tip: snapshot.block_hash,
+            target: snapshot.target,
+            utxos: snapshot.utxos.into_iter()
+                    .map(|(hash, output)| (hash, (false, output)))
+                    .collect(),
+            fee_history: VecDeque::new(),
+        };
+        //what was saved before is replaced along with its journal
+        let (mut chainstate, _, _) = Self::open_chainstate(&store)?;
+        chainstate.save(&state)?;
+        return Self::open_at(store, chainstate, state, Vec::new());
+    }
+
+    /* start from state with the changes journaled since it was
+    saved and replay the blocks stored after it. Changes for blocks
+    the store no longer has are taken back first, with the outputs
+    they spent kept in the journal */
+    fn open_at(store: BlockStore, mut chainstate: Journaled, mut state: ChainState,
+               changes: Vec<ChainDelta>) -> IOResult<Self> {
+        let mut applied = Vec::new();
+        for change in changes {
+            applied.push(state.apply(change));
+        }
+        //a snapshot ahead of a store with no blocks is waiting for
+        //its headers
+        let pending = state.height > store.len() && store.pruned_height() == store.len();
+        let mut rolled_back = false;
+        while !pending && state.height > 0 && store.hash(state.height - 1) != Some(state.tip) {
+            let Some(reverse) = applied.pop() else {
+                return Err(IOError::new(IOErrorKind::InvalidData,
+                    "Chain state does not match the block store"));
+            };
+            state.apply(reverse);
+            rolled_back = true;
+        }
+        if rolled_back {
+            chainstate.save(&state)?;
+        }
+
+        let mut blockchain = BlockChain::new();
+        blockchain.utxos = state.utxos;
+        blockchain.target = state.target;
+        blockchain.fee_history = state.fee_history;
+        blockchain.store = Some(store);
+        blockchain.chainstate = Some(chainstate);
+        blockchain.pending_snapshot = pending.then_some((state.height, state.tip));
+        for height in state.height..blockchain.block_height() {
+            let block = blockchain.read_block(height)?.ok_or_else(|| {
+                IOError::new(IOErrorKind::InvalidData, "Missing block in store")
+            })?;
+            let fee_rate = if height == 0 {
+                None
+            } else {
+                Some(blockchain.median_fee_rate(&block).map_err(|e| {
+                    IOError::new(IOErrorKind::InvalidData, e)
+                })?)
+            };
+            let undo = blockchain.undo_for(&block);
+            blockchain.connect_block(&block, &undo, height + 1, fee_rate);
+        }
+        return Ok(blockchain);
+    }
+
+    //height of the snapshot the chain was opened from while the
+    //headers up to it are still missing
+    pub fn pending_snapshot(self: &Self) -> Option<u64> {
+        return self.pending_snapshot.map(|(height, _)| height);
+    }
+
+    /* store headers below the pending snapshot, which must follow
+    on from the ones so far. Once there are headers up to it the
+    last must be the snapshot's block, or they are all dropped
+    again. Their blocks are never needed, the snapshot stands in
+    for them */
+    pub fn add_headers(self: &mut Self, headers: &[BlockHeader]) -> Result<()> {
+        let Some((height, tip)) = self.pending_snapshot else {
+            return Err(BtcError::InvalidBlockHeader);
+        };
+        let len = self.block_height();
+        if len + headers.len() as u64 > height {
+            return Err(BtcError::InvalidBlockHeader);
+        }
+        BlockHeader::check_chain(len, &self.last_headers(len), headers)?;
+
+        let store = self.store.as_mut().ok_or(BtcError::BlockStoreFailure)?;
+        for header in headers {
+            store.append_header(header).map_err(|_| BtcError::BlockStoreFailure)?;
+        }
+        if store.len() < height {
+            return Ok(());
+        }
+        if store.last_hash() != Some(tip) {
+            store.truncate(0).map_err(|_| BtcError::BlockStoreFailure)?;
+            return Err(BtcError::InvalidSnapshot);
+        }
+        self.pending_snapshot = None;
+        return Ok(());
+    }
+
+    /* headers of the last DIFFICULTY_UPDATE_INETRVAL of the first
+    height blocks, or all of them if there are fewer. What
+    check_chain needs of the chain before new headers */
+    pub fn last_headers(self: &Self, height: u64) -> Vec<BlockHeader> {
+        let first = height.saturating_sub(crate::DIFFICULTY_UPDATE_INETRVAL);
+        return (first..height).map_while(|height| self.header(height)).collect();
+    }
+
+    //utxo set as of the first height blocks
+    pub fn utxo_snapshot(self: &Self, height: u64) -> Result<UtxoSnapshot> {
+        if height > self.block_height() || self.pending_snapshot.is_some() {
+            return Err(BtcError::InvalidSnapshot);
+        }
+        if height == self.block_height() {
+            return Ok(self.snapshot_of(height));
+        }
+        let replay = self.replay_to(height, |height| self.block(height))?;
+        return Ok(replay.snapshot_of(height));
+    }
+
+    /* replay the blocks up to the snapshot, checking them as
+    add_block does, and check that they end with the same utxo
+    set. The blocks come from block_at, which may fetch the ones
+    the chain does not have. Meant to run in the background after
+    opening from a snapshot */
+    pub fn verify_snapshot(self: &Self, snapshot: &UtxoSnapshot,
+                           block_at: impl FnMut(u64) -> Option<Block>) -> Result<()> {
+        if snapshot.height > self.block_height() {
+            return Err(BtcError::InvalidSnapshot);
+        }
+        let replayed = self.replay_to(snapshot.height, block_at)?.snapshot_of(snapshot.height);
+        if replayed.commitment() != snapshot.commitment() {
+            return Err(BtcError::InvalidSnapshot);
+        }
+        return Ok(());
+    }
+
+    fn snapshot_of(self: &Self, height: u64) -> UtxoSnapshot {
+        let block_hash = match height {
+            0 => Hash::zero(),
+            height => self.block_hash(height - 1).unwrap_or_else(Hash::zero),
+        };
+        let utxos = self.utxos.iter().map(|(hash, (_, output))| (*hash, output.clone()));
+        return UtxoSnapshot::new(height, block_hash, self.target, utxos);
+    }
+
+    /* utxos and target after the first height blocks, rebuilt by
+    checking each block from block_at as add_block does. They must
+    be the blocks this chain has headers for. The copy reads
+    headers from the same place as this chain, so its own
+    block_height is not meaningful */
+    fn replay_to(self: &Self, height: u64,
+                 mut block_at: impl FnMut(u64) -> Option<Block>) -> Result<BlockChain> {
+        let mut replay = BlockChain::new();
+        replay.store = self.store.clone();
+        if self.store.is_none() {
+            replay.blocks = self.blocks[..height as usize].to_vec();
+        }
+        for height in 0..height {
+            let block = block_at(height).ok_or(BtcError::BlockNotAvailable)?;
+            if Some(block.hash()) != self.block_hash(height) {
+                return Err(BtcError::InvalidBlock);
+            }
+            let fee_rate = replay.check_block(&block, height)?;
+            let undo = replay.undo_for(&block);
+            replay.connect_block(&block, &undo, height + 1, fee_rate);
+        }
+        return Ok(replay);
+    }
+
+    /* write the whole utxo set next to the block store. The changes
+    of each block are journaled as it comes in, this only folds the
+    journal into the saved state */
+    pub fn save_chainstate(self: &mut Self) -> IOResult<()> {
+        if self.chainstate.is_none() {
+            return Err(IOError::new(IOErrorKind::Unsupported,
+                "Blockchain has no block store"));
+        }
+        let state = ChainState {
+            height: self.block_height(),
+            tip: self.last_block_hash(),
+            target: self.target,
+            utxos: self.utxos.clone(),
+            fee_history: self.fee_history.clone(),
+        };
+        return self.chainstate.as_mut().expect("BUG: Impossible").save(&state);
+    }
+
+    /* journal what a block changed, saving the whole chain state
+    instead once the journal has grown or could not be written.
+    Returns whether the chain state on disk is up to date */
+    fn record(self: &mut Self, delta: &ChainDelta) -> bool {
+        let Some(chainstate) = &mut self.chainstate else {
+            return true;
+        };
+        if let Err(e) = chainstate.append(delta) {
+            eprintln!("Failed to journal chain state: {}", e);
+        }
+        if !chainstate.needs_save() {
+            return true;
+        }
+        if let Err(e) = self.save_chainstate() {
+            eprintln!("Failed to save chain state: {}", e);
+            return false;
+        }
+        return true;
+    }
+
+    /* start keeping the txid and pubkey indexes. A chain in a store
+    picks up the index saved there with the blocks journaled since,
+    takes back those the chain no longer has and catches up from
+    its blocks. Otherwise it is built from all the blocks. Fails if
+    some of the blocks it needs were pruned */
+    pub fn enable_index(self: &mut Self) -> Result<()> {
+        if self.index.is_some() {
+            return Ok(());
+        }
+        let empty = || SavedIndex { height: 0, tip: Hash::zero(), index: ChainIndex::new() };
+        let mut saved = empty();
+        let mut journal = None;
+        let mut changed = false;
+        if let Some(store) = &self.store {
+            let (index_journal, base, changes): (Journaled, Option<SavedIndex>, Vec<IndexDelta>) =
+                Journaled::open(store.dir().join(TXINDEX_FILE), store.dir().join(TXINDEX_JOURNAL_FILE))
+                .map_err(|_| BtcError::BlockStoreFailure)?;
+            saved = base.unwrap_or_else(empty);
+            let mut applied = Vec::new();
+            for change in changes {
+                saved.index.apply(&change);
+                let before = (saved.height, saved.tip);
+                saved.height = change.chain_height();
+                saved.tip = change.tip;
+                applied.push((before, change));
+            }
+            //index kept while the chain went another way
+            while saved.height > 0 && self.block_hash(saved.height - 1) != Some(saved.tip) {
+                changed = true;
+                let Some(((height, tip), change)) = applied.pop() else {
+                    saved = empty();
+                    break;
+                };
+                saved.index.revert(&change);
+                saved.height = height;
+                saved.tip = tip;
+            }
+            journal = Some(index_journal);
+        }
+
+        for height in saved.height..self.block_height() {
+            let block = self.block(height).ok_or(BtcError::BlockNotAvailable)?;
+            saved.index.connect(&block, height);
+            changed = true;
+        }
+        saved.height = self.block_height();
+        saved.tip = self.last_block_hash();
+        if changed && let Some(journal) = &mut journal {
+            journal.save(&saved).map_err(|_| BtcError::BlockStoreFailure)?;
+        }
+        self.index = Some(saved.index);
+        self.index_journal = journal;
+        return Ok(());
+    }
+
+    /* connect or disconnect the block at height in the index, if
+    there is one, and journal it. tip is the last block after it.
+    The whole index is saved instead once the journal has grown or
+    could not be written */
+    fn index_block(self: &mut Self, connect: bool, block: &Block, height: u64, tip: Hash) {
+        let Some(index) = &mut self.index else {
+            return;
+        };
+        let delta = IndexDelta {
+            connect,
+            height,
+            tip,
+            transactions: IndexedTransaction::of_block(block),
+        };
+        index.apply(&delta);
+        let Some(journal) = &mut self.index_journal else {
+            return;
+        };
+        if let Err(e) = journal.append(&delta) {
+            eprintln!("Failed to journal index: {}", e);
+        }
+        if !journal.needs_save() {
+            return;
+        }
+        let saved = SavedIndex { height: delta.chain_height(), tip, index: index.clone() };
+        if let Err(e) = journal.save(&saved) {
+            eprintln!("Failed to save index: {}", e);
+        }
+    }
+
+    pub fn index(self: &Self) -> Option<&ChainIndex> {
+        return self.index.as_ref();
+    }
+
+    //confirmed transaction by txid, needs the index
+    pub fn transaction(self: &Self, txid: &Hash) -> Option<(TxLocation, Transactions)> {
+        let location = self.index.as_ref()?.transaction(txid)?;
+        let block = self.block(location.height)?;
+        let transaction = block.transactions.get(location.position as usize)?;
+        return Some((location, transaction.clone()));
+    }
+
+    //every confirmed output paid to pubkey, needs the index
+    pub fn history(self: &Self, pubkey: &PublicKey) -> Option<Vec<OutputRecord>> {
+        let index = self.index.as_ref()?;
+        return Some(index.history(pubkey).into_iter().cloned().collect());
+    }
+
+    /* unspent outputs paid to pubkey and whether a pending tx
+    spends them. Looked up in the index when there is one, the
+    whole utxo set is scanned otherwise */
+    pub fn utxos_for(self: &Self, pubkey: &PublicKey) -> Vec<(TransactionsOutput, bool)> {
+        let Some(index) = &self.index else {
+            return self.utxos.values()
+                    .filter(|(_, output)| output.lock.is_owned_by(pubkey))
+                    .map(|(marked, output)| (output.clone(), *marked))
+                    .collect();
+        };
+        return index.history(pubkey).into_iter()
+                .filter(|record| record.spent.is_none())
+                .filter_map(|record| self.utxos.get(&record.hash))
+                .map(|(marked, output)| (output.clone(), *marked))
+                .collect();
+    }
+
+    /* keep only the last keep full blocks, at least
+    MIN_BLOCKS_TO_KEEP, and the headers of all of them. Older
+    block files are deleted now and as new blocks come in. The
+    chain state is saved first, opening replays the blocks after
+    the saved one and those must still be there */
+    pub fn set_prune(self: &mut Self, keep: u64) -> IOResult<()> {
+        self.save_chainstate()?;
+        let Some(store) = &mut self.store else {
+            return Err(IOError::new(IOErrorKind::Unsupported,
+                "Blockchain has no block store"));
+        };
+        let keep = keep.max(crate::MIN_BLOCKS_TO_KEEP);
+        self.prune = Some(keep);
+        return store.prune(keep);
+    }
+
+    //lowest height whose full block is still kept
+    pub fn pruned_height(self: &Self) -> u64 {
+        return self.store.as_ref().map(|store| store.pruned_height()).unwrap_or(0);
+    }
+
+    fn read_block(self: &Self, height: u64) -> IOResult<Option<Block>> {
+        return match &self.store {
+            Some(store) => store.block(height),
+            None => Ok(self.blocks.get(height as usize).cloned()),
+        };
+    }
+
+    pub fn block(self: &Self, height: u64) -> Option<Block> {
+        return self.read_block(height).unwrap_or_else(|e| {
+            eprintln!("Failed to read block {}: {}", height, e);
+            None
+        });
+    }
+
+    //headers are kept for pruned blocks too
+    pub fn header(self: &Self, height: u64) -> Option<BlockHeader> {
+        let header = match &self.store {
+            Some(store) => store.header(height),
+            None => Ok(self.blocks.get(height as usize).map(|block| block.header.clone())),
+        };
+        return header.unwrap_or_else(|e| {
+            eprintln!("Failed to read header {}: {}", height, e);
+            None
+        });
+    }
+
+    pub fn block_hash(self: &Self, height: u64) -> Option<Hash> {
+        return match &self.store {
+            Some(store) => store.hash(height),
+            None => self.blocks.get(height as usize).map(|block| block.hash()),
+        };
+    }
+
+    pub fn height_of(self: &Self, hash: &Hash) -> Option<u64> {
+        return match &self.store {
+            Some(store) => store.height(hash),
+            None => self.blocks.iter().position(|block| block.hash() == *hash)
+                        .map(|height| height as u64),
+        };
+    }
+
+    /* hashes back from the tip, one by one for the last ten
+    blocks then twice as far apart each time, ending with the
+    first block. A peer finds where our chains part with it */
+    pub fn locator(self: &Self) -> Vec<Hash> {
+        let mut locator = Vec::new();
+        let Some(mut height) = self.block_height().checked_sub(1) else {
+            return locator;
+        };
+        let mut step = 1;
+        loop {
+            locator.push(self.block_hash(height).expect("BUG: Impossible"));
+            if height == 0 {
+                return locator;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+    }
+
+    //height of the first locator hash on this chain
+    pub fn find_fork(self: &Self, locator: &[Hash]) -> Option<u64> {
+        return locator.iter().find_map(|hash| self.height_of(hash));
+    }
+
+    //retained blocks in height order
+    pub fn blocks(self: &Self) -> impl Iterator<Item = Block> + '_ {
+        return (0..self.block_height()).filter_map(|height| self.block(height));
     }
 
     pub fn block_height(self: &Self) -> u64 {
-        return self.blocks.len() as u64;
+        return match &self.store {
+            Some(store) => store.len(),
+            None => self.blocks.len() as u64,
+        };
+    }
+
+    //hash of the last block, zero before the first one
+    pub fn last_block_hash(self: &Self) -> Hash {
+        let last = match &self.store {
+            Some(store) => store.last_hash(),
+            None => self.blocks.last().map(|block| block.hash()),
+        };
+        return last.unwrap_or_else(Hash::zero);
     }
 
-    pub fn mempool(self: &Self) -> &[(DateTime<Utc>, Transactions)] {
-        //need to implement time tracking
+    pub fn mempool(self: &Self) -> &Mempool {
         return &self.mempool;
     }
 
+    //cap the mempool at max_size bytes, evicting what no longer fits
+    pub fn set_max_mempool_size(self: &mut Self, max_size: u64) {
+        self.mempool.set_max_size(max_size);
+        self.trim_mempool();
+    }
+
     pub fn add_block(self: &mut Self, block: Block) -> Result<()> {
 
-        if self.blocks.is_empty() {
+        //blocks below a snapshot are never added, its headers are
+        if self.pending_snapshot.is_some() {
+            println!("headers up to the snapshot are missing");
+            return Err(BtcError::InvalidBlock);
+        }
+        let height = self.block_height();
+        let fee_rate = self.check_block(&block, height)?;
+
+        //blocks are on disk before any state changes
+        let undo = self.undo_for(&block);
+        match &mut self.store {
+            Some(store) => {
+                store.append(&block, &undo).map_err(|_| BtcError::BlockStoreFailure)?;
+            }
+            None => {
+                self.blocks.push(block.clone());
+                self.undo.push(undo.clone());
+            }
+        }
+        let saved = self.connect_block(&block, &undo, height + 1, fee_rate);
+
+        /* the block is in, failing to prune only costs disk space.
+        Blocks are only pruned while the chain state on disk is up
+        to date, it may need them to catch up when opened */
+        if saved
+            && let (Some(store), Some(keep)) = (&mut self.store, self.prune)
+            && let Err(e) = store.prune(keep) {
+            eprintln!("Failed to prune blocks: {}", e);
+        }
+        return Ok(());
+    }
+
+    /* check block as the one after the first height blocks, against
+    the utxos and target the chain has after them. Returns the
+    median fee rate its transactions paid, none for the first */
+    fn check_block(self: &Self, block: &Block, height: u64) -> Result<Option<u64>> {
+
+        if block.size() as u64 > crate::MAX_BLOCK_SIZE {
+            println!("block too big");
+            return Err(BtcError::InvalidBlock);
+        }
+
+        let mut fee_rate = None;
+        if height == 0 {
             //if this is first block, check if block's
             //prev_block_hash is all zeros
             if block.header.prev_block_hash != Hash::zero() {
@@ -66,12 +625,18 @@ impl BlockChain {
         } else {
             //if this is not first block, check if block's 
             //prev_block_hash is hash of the last block
-            let last_block = self.blocks.last().unwrap();
-            if block.header.prev_block_hash != last_block.hash() {
+            let last_header = self.header(height - 1).ok_or(BtcError::BlockStoreFailure)?;
+            if Some(block.header.prev_block_hash) != self.block_hash(height - 1) {
                 println!("previous hash is wrong");
                 return Err(BtcError::InvalidBlock);
             }
 
+            //block is mined at the chain's current target
+            if block.header.target != self.target {
+                println!("wrong target");
+                return Err(BtcError::InvalidBlock);
+            }
+
             //block's hash is less than the target
             if !block.header.hash().matches_target(block.header.target) {
                 println!("doesn't match target");
@@ -86,228 +651,572 @@ impl BlockChain {
             }
 
             //block's timestamp is after the last block timestamp
-            if block.header.timestamp <= last_block.header.timestamp
+            if block.header.timestamp <= last_header.timestamp
             {
                 return Err(BtcError::InvalidBlock);
             }
 
             //verify all transactions in the block
-            block.verify_transaction(self.block_height()
-                            , &self.utxos)?;
+            block.verify_transaction(height, &self.utxos)?;
+
+            //remember what its transactions paid for fee estimation
+            fee_rate = Some(self.median_fee_rate(block)?);
         }
+        return Ok(fee_rate);
+    }
 
-        //Remove tx from mempool that are now in the block
-        let block_transactions: HashSet<_> = block
-                                    .transactions
-                                    .iter()
-                                    .map(|tx| tx.hash())
-                                    .collect();
-        self.mempool.retain(|(_, tx)| {
-            !block_transactions.contains(&tx.hash())
+    fn undo_at(self: &Self, height: u64) -> Option<BlockUndo> {
+        return match &self.store {
+            Some(store) => store.undo(height).unwrap_or_else(|e| {
+                eprintln!("Failed to read undo data {}: {}", height, e);
+                None
+            }),
+            None => {
+                let first = (self.blocks.len() - self.undo.len()) as u64;
+                height.checked_sub(first).and_then(|idx| self.undo.get(idx as usize).cloned())
+            }
+        };
+    }
+
+    //whether the blocks from height on have undo data
+    fn can_disconnect_to(self: &Self, height: u64) -> bool {
+        return match &self.store {
+            Some(store) => height >= store.pruned_height(),
+            None => height as usize >= self.blocks.len() - self.undo.len(),
+        };
+    }
+
+    /* take the last block off the chain, putting back the outputs
+    it spent. The mempool is left to the caller */
+    fn disconnect_tip(self: &mut Self) -> Result<Block> {
+        let height = self.block_height().checked_sub(1).ok_or(BtcError::InvalidBlock)?;
+        let block = self.block(height).ok_or(BtcError::BlockNotAvailable)?;
+        let undo = self.undo_at(height).ok_or(BtcError::BlockNotAvailable)?;
+
+        for transaction in &block.transactions {
+            for output in &transaction.outputs {
+                self.utxos.remove(&output.hash());
+            }
+        }
+        for (hash, output) in &undo.spent {
+            self.utxos.insert(*hash, (false, output.clone()));
+        }
+        //only its own fee rate goes, the older one it pushed out
+        //of the history is lost
+        if height > 0 {
+            self.fee_history.pop_back();
+        }
+
+        //blocks after the first are mined at the chain's target,
+        //so the last header has it before any adjustment
+        self.target = match height {
+            0 | 1 => crate::MIN_TARGET,
+            height => self.header(height - 1).ok_or(BtcError::BlockStoreFailure)?.target,
+        };
+        self.adjust_target(height);
+
+        //the chain state on disk goes back first, if the store is
+        //not truncated after all the block is replayed on opening
+        let tip = match height {
+            0 => Hash::zero(),
+            height => self.block_hash(height - 1).ok_or(BtcError::BlockStoreFailure)?,
+        };
+        self.index_block(false, &block, height, tip);
+        self.record(&ChainDelta {
+            height,
+            tip,
+            target: self.target,
+            fee_history: self.fee_history.clone(),
+            removed: created_outputs(&block),
+            added: undo.spent,
         });
-        self.blocks.push(block);
-        self.try_adjust_target();
-        return Ok(());
+        match &mut self.store {
+            Some(store) => store.truncate(height).map_err(|_| BtcError::BlockStoreFailure)?,
+            None => {
+                self.blocks.pop();
+                self.undo.pop();
+            }
+        }
+        return Ok(block);
     }
 
-    pub fn rebuild_utxos(self: &mut Self) {
+    /* switch to the fork that keeps the first from blocks and
+    continues with blocks, if it has more work than the blocks it
+    replaces. The old blocks are put back if a new one turns out
+    invalid. Their transactions go back to the mempool. Failing to
+    put them back leaves the chain in no state to go on with, that
+    is ChainInconsistent and the caller must stop using it. The
+    chain state on disk is rolled back to the store when opened */
+    pub fn reorganize(self: &mut Self, from: u64, blocks: Vec<Block>) -> Result<()> {
+        let height = self.block_height();
+        if from > height {
+            return Err(BtcError::InvalidBlock);
+        }
+        if !self.can_disconnect_to(from) {
+            return Err(BtcError::BlockNotAvailable);
+        }
+        let old_work = (from..height).filter_map(|height| self.header(height))
+                        .fold(U256::zero(), |work, header| work.saturating_add(header.work()));
+        let new_work = blocks.iter()
+                        .fold(U256::zero(), |work, block| work.saturating_add(block.header.work()));
+        if new_work <= old_work {
+            return Err(BtcError::InsufficientChainWork);
+        }
 
-        for block in &self.blocks {
-            for transaction in &block.transactions {
-                for input in &transaction.inputs {
-                    self.utxos.remove(
-                        &input.prev_transaction_output_hash,
-                    );
+        let mut disconnected = Vec::new();
+        while self.block_height() > from {
+            disconnected.push(self.disconnect_tip()?);
+        }
+        let mut result = Ok(());
+        for block in blocks {
+            if let Err(e) = self.add_block(block) {
+                if let Err(e) = self.restore_blocks(from, &disconnected) {
+                    eprintln!("Failed to restore blocks after a failed reorganization: {}", e);
+                    return Err(BtcError::ChainInconsistent);
                 }
-                for output in transaction.outputs.iter() {
-                    self.utxos.insert(output.hash(), (false, output.clone()));
+                result = Err(e);
+                break;
+            }
+        }
+
+        //old transactions first, the new blocks may have confirmed
+        //them or some of what was pending
+        let now = Utc::now();
+        let mut transactions: Vec<(DateTime<Utc>, Transactions)> = disconnected.iter().rev()
+                        .flat_map(|block| block.transactions.iter().skip(1))
+                        .map(|transaction| (now, transaction.clone()))
+                        .collect();
+        transactions.extend(self.mempool.transactions());
+        self.restore_mempool(SavedMempool { transactions });
+
+        //fold the steps of the switch into one saved chain state
+        if self.chainstate.is_some()
+            && let Err(e) = self.save_chainstate() {
+            eprintln!("Failed to save chain state: {}", e);
+        }
+        return result;
+    }
+
+    //go back to the first from blocks followed by blocks, which
+    //were the chain before
+    fn restore_blocks(self: &mut Self, from: u64, blocks: &[Block]) -> Result<()> {
+        while self.block_height() > from {
+            self.disconnect_tip()?;
+        }
+        for block in blocks.iter().rev() {
+            self.add_block(block.clone())?;
+        }
+        return Ok(());
+    }
+
+    //outputs from before block that it spends
+    fn undo_for(self: &Self, block: &Block) -> BlockUndo {
+        let spent = block.transactions.iter()
+                        .flat_map(|transaction| &transaction.inputs)
+                        .filter_map(|input| {
+                            let hash = input.prev_transaction_output_hash;
+                            self.utxos.get(&hash).map(|(_, output)| (hash, output.clone()))
+                        })
+                        .collect();
+        return BlockUndo { spent };
+    }
+
+    /* update the state for a stored block that is now the last of
+    height blocks, undo holding the outputs it spent. Returns
+    whether the chain state on disk is up to date */
+    fn connect_block(self: &mut Self, block: &Block, undo: &BlockUndo, height: u64,
+                     fee_rate: Option<u64>) -> bool {
+        if let Some(fee_rate) = fee_rate {
+            if self.fee_history.len() == crate::FEE_HISTORY_BLOCKS {
+                self.fee_history.pop_front();
+            }
+            self.fee_history.push_back(fee_rate);
+        }
+
+        //Remove tx from mempool that are now in the block
+        //or spend the same outputs as one in the block
+        for transaction in &block.transactions {
+            self.mempool.confirm(&transaction.hash());
+            for input in &transaction.inputs {
+                if let Some(conflict) = self.mempool.spender(&input.prev_transaction_output_hash) {
+                    self.remove_from_mempool(&conflict);
                 }
             }
         }
+        self.apply_utxos(block);
+        self.index_block(true, block, height - 1, block.hash());
+        self.adjust_target(height);
+        return self.record(&ChainDelta {
+            height,
+            tip: block.hash(),
+            target: self.target,
+            fee_history: self.fee_history.clone(),
+            removed: undo.spent.clone(),
+            added: created_outputs(block),
+        });
+    }
+
+    fn median_fee_rate(self: &Self, block: &Block) -> Result<u64> {
+        let mut fee_rates = block.fee_rates(&self.utxos)?;
+        fee_rates.sort();
+        return Ok(fee_rates.get(fee_rates.len() / 2).copied().unwrap_or(0));
+    }
+
+    /* fee per byte for confirmation within target blocks, the
+    higher of what recent blocks and the current mempool ask for */
+    pub fn estimate_fee_rate(self: &Self, target: u32) -> u64 {
+        let target = target.max(1);
+        return self.fee_rate_from_blocks(target)
+                    .max(self.fee_rate_from_mempool(target))
+                    .max(self.mempool.min_fee_rate());
+    }
+
+    /*a transaction beats a block's median fee rate with some
+    probability p, so it is confirmed within target blocks with
+    probability 1 - (1 - p)^target. Pick the recent block median
+    that makes that 95%*/
+    fn fee_rate_from_blocks(self: &Self, target: u32) -> u64 {
+        if self.fee_history.is_empty() {
+            return 0;
+        }
+        let mut medians: Vec<u64> = self.fee_history.iter().copied().collect();
+        medians.sort();
+        let p = 1.0 - 0.05f64.powf(1.0 / target as f64);
+        let idx = ((p * medians.len() as f64).ceil() as usize)
+                    .clamp(1, medians.len()) - 1;
+        return medians[idx];
+    }
+
+    //fee rate needed to get ahead of everything in the mempool
+    //that already fills the next target blocks
+    fn fee_rate_from_mempool(self: &Self, target: u32) -> u64 {
+        let capacity = target as u64 * crate::MAX_BLOCK_SIZE;
+        let mut size = 0;
+        for entry in self.mempool.iter() {
+            size += entry.size;
+            if size > capacity {
+                return entry.fee_rate().per_byte() + 1;
+            }
+        }
+        return 0;
+    }
+
+    pub fn rebuild_utxos(self: &mut Self) {
+        for height in 0..self.block_height() {
+            if let Some(block) = self.block(height) {
+                self.apply_utxos(&block);
+            }
+        }
+    }
+
+    /*spend a block's inputs and add its outputs, marking
+    outputs that pending transactions already spend*/
+    fn apply_utxos(self: &mut Self, block: &Block) {
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                self.utxos.remove(
+                    &input.prev_transaction_output_hash,
+                );
+            }
+            for output in transaction.outputs.iter() {
+                let hash = output.hash();
+                let marked = self.mempool.spender(&hash).is_some();
+                self.utxos.insert(hash, (marked, output.clone()));
+            }
+        }
     }
 
     //try to adjust the target of the blockchain
     pub fn try_adjust_target(self: &mut Self) {
-        if self.blocks.is_empty() {
+        self.adjust_target(self.block_height());
+    }
+
+    //adjust the target once the chain is height blocks long
+    fn adjust_target(self: &mut Self, height: u64) {
+        if height == 0 {
             return;
         }
-        if self.blocks.len() % crate::DIFFICULTY_UPDATE_INETRVAL as usize != 0 {
+        if !height.is_multiple_of(crate::DIFFICULTY_UPDATE_INETRVAL) {
             return;
         }
 
         //time to mine the last crate::DIFFICULTY_UPDATE_INTERVAL blocks
-        let start_time = self.blocks[self.blocks.len()
-            - crate::DIFFICULTY_UPDATE_INETRVAL as usize]
-            .header
-            .timestamp;
-        let end_time = self.blocks.last().unwrap().header.timestamp;
-        let time_diff = (end_time - start_time).num_seconds();
-        let target_seconds = crate::IDEAL_BLOCK_TIME
-                        * crate::DIFFICULTY_UPDATE_INETRVAL;
-        //multiply the current target by actual time divided by
-        //ideal time
-
-        /*let new_target = self.target
-            * (time_diff as f64 / target_seconds as f64) as usize; */
-        
-        let new_target = BigDecimal::parse_bytes(
-            &self.target.to_string().as_bytes(), 10)
-            .expect("BUG: impossible")
-            *(BigDecimal::from(time_diff)
-               / BigDecimal::from(target_seconds));
-        
-        let new_target_str = new_target.to_string()
-                                        .split(".")
-                                        .next()
-                                        .expect("BUG: Expected a decimal point")
-                                        .to_owned();
-        
-        let new_target = U256::from_str_radix(&new_target_str, 10)
-                                                            .expect("BUG: Impossible");
-
-        //4 * self.target > new_target > self.target / 4
-        let new_target = if new_target < self.target / 4 {
-            self.target / 4
-        }else if new_target > self.target * 4 {
-            self.target * 4
-        } else {
-            new_target
+        let (Some(first), Some(last)) = (
+            self.header(height - crate::DIFFICULTY_UPDATE_INETRVAL),
+            self.header(height - 1),
+        ) else {
+            return;
         };
-
-        //if new_target > minimum target
-        //set it to the minmum target
-        self.target = new_target.min(crate::MIN_TARGET);
-
+        self.target = BlockHeader::retarget(self.target, &first, &last);
     }
 
     pub fn add_to_mempool(self: &mut Self, transaction: Transactions) -> Result<()> {
+        return self.add_to_mempool_at(transaction, Utc::now());
+    }
+
+    fn add_to_mempool_at(self: &mut Self, transaction: Transactions,
+                         added_at: DateTime<Utc>) -> Result<()> {
+
+        if self.mempool.contains(&transaction.hash()) {
+            println!("Already in mempool");
+            return Err(BtcError::InvalidTransaction);
+        }
 
         //validation pf tx before insertion
-        //all inputs must match known UTXO's and must be unique
+        //all inputs must match known UTXO's or outputs of
+        //pending tx and must be unique
         let mut known_inputs = HashSet::new();
+        let mut all_inputs: u64 = 0;
         for input in &transaction.inputs {
-            if !self.utxos.contains_key(
-                &input.prev_transaction_output_hash,
-            ) {
+            let hash = input.prev_transaction_output_hash;
+            let prev_output = self.utxos.get(&hash)
+                                .map(|(_, output)| output)
+                                .or_else(|| self.mempool.output(&hash));
+            let Some(prev_output) = prev_output else {
                 println!("Utxos not found");
-                dbg!(&self.utxos());
                 return Err(BtcError::InvalidTransaction);
-            }
-            if known_inputs.contains(
-                &input.prev_transaction_output_hash
-            ) {
+            };
+            if known_inputs.contains(&hash) {
                 println!("Duplicate input");
                 return Err(BtcError::InvalidTransaction);
             }
-            known_inputs.insert(input.prev_transaction_output_hash);
-        }
-
-        /*if any utxos have bool set to true find the tx that ref them in mempool
-        , remove it, and set all the utxos it references to false*/
-        for input in &transaction.inputs {
-            if let Some((true, _)) = self.utxos.get(&input.prev_transaction_output_hash) {
-                /*tx that ref the utxos we are trying to ref*/
-                let ref_tx = self.mempool.iter().enumerate()
-                                                                .find(|(_, (_, tx))| {
-                                                                    tx.outputs.iter().any(|output| {
-                                                                        output.hash() == input.prev_transaction_output_hash
-                                                                    })
-                                                                });
-                
-                //unmark utxos if found
-                if let Some((idx,( _, ref_tx))) = ref_tx {
-                    for input in &ref_tx.inputs {
-                        //setting all utxos from this tx to false
-                        self.utxos.entry(input.prev_transaction_output_hash)
-                        .and_modify(|(marked, _)| {
-                            *marked = false;
-                        });
-                    }
-                    //remove the tx from mempool
-                    self.mempool.remove(idx);
-                } else {
-                    //if somehow there is no matching tx
-                    //set this utxo to false
-                    self.utxos.entry(input.prev_transaction_output_hash)
-                    .and_modify(|(marked, _)| {
-                        *marked = false;
-                    });
-                }
+            known_inputs.insert(hash);
+            //checked before anything is replaced, so only the owner
+            //of an output can evict the tx spending it
+            let Some(pubkey) = prev_output.lock.spending_key(input.pubkey.as_ref()) else {
+                println!("Input does not match the output lock");
+                return Err(BtcError::InvalidTransaction);
+            };
+            if !input.signature.verify(&hash, pubkey) {
+                println!("Invalid signature");
+                return Err(BtcError::InvalidSignature);
             }
+            all_inputs = all_inputs.checked_add(prev_output.value)
+                                .ok_or(BtcError::InvalidTransaction)?;
         }
 
         //all inputs must be lower than all outputs
-        let all_inputs = transaction.inputs.iter()
-                                .map(|input| {
-                                        self.utxos.get(&input.prev_transaction_output_hash)
-                                        .expect("BUG: Impossible")
-                                        .1.value
-                                }).sum::<u64>();
-
         let all_outputs = transaction.outputs.iter()
-                                    .map(|output| output.value)
-                                    .sum();
+                                    .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+                                    .ok_or(BtcError::InvalidTransaction)?;
         
         if all_inputs < all_outputs {
             println!("Inputs are lower than outputs");
             return Err(BtcError::InvalidTransaction);
         }
 
-        //Marking Utxos as used
+        //fee rate must clear the min relay fee and the rolling
+        //min fee of a full mempool
+        let fee = all_inputs - all_outputs;
+        let size = transaction.size() as u64;
+        if fee < self.mempool.min_fee_rate() * size {
+            println!("Fee rate too low");
+            return Err(BtcError::InsufficientFee);
+        }
+
+        //tx that already spend the same outputs get replaced if
+        //this one pays more, unless this tx spends from them
+        let ancestors = self.mempool.ancestors_of(&transaction);
+        let conflicts: HashSet<Hash> = transaction.inputs.iter()
+                                .filter_map(|input| {
+                                    self.mempool.spender(&input.prev_transaction_output_hash)
+                                })
+                                .collect();
+        if conflicts.iter().any(|conflict| ancestors.contains(conflict)) {
+            println!("Spends from a tx it conflicts with");
+            return Err(BtcError::InvalidTransaction);
+        }
+        self.mempool.check_replacement(&conflicts, fee, size)?;
+        self.mempool.check_limits(&transaction)?;
+
+        /*replace the conflicting tx with everything spending from
+        them and make room if it is full, this tx may be the one to
+        go. If it does not get in the mempool is left as it was*/
+        let inputs: Vec<Hash> = transaction.inputs.iter()
+                                    .map(|input| input.prev_transaction_output_hash)
+                                    .collect();
+        let evicted = self.mempool.add(transaction, fee, added_at, &conflicts).inspect_err(|e| {
+            if let BtcError::InsufficientFee = e {
+                println!("Mempool full");
+            }
+        })?;
+
+        //unmark the utxos evicted tx referenced, then mark the ones
+        //this tx spends, some may be the same
+        for entry in &evicted {
+            self.unmark_inputs(&entry.transaction);
+        }
+        for hash in inputs {
+            self.utxos.entry(hash).and_modify(|(marked, _)| {
+                *marked = true;
+            });
+        }
+        return Ok(());
+    }
+
+    fn trim_mempool(self: &mut Self) {
+        for entry in self.mempool.trim() {
+            self.unmark_inputs(&entry.transaction);
+        }
+    }
+
+    fn unmark_inputs(self: &mut Self, transaction: &Transactions) {
         for input in &transaction.inputs {
             self.utxos.entry(input.prev_transaction_output_hash)
-                    .and_modify(|(marked, _)| {
-                        *marked = true;
-                    });
-        }
-
-        //push tx to mempool
-        self.mempool.push((Utc::now(), transaction));
-        //sort by miner fee
-        self.mempool.sort_by_key(|(_, transaction)| {
-            let all_inputs = transaction.inputs.iter()
-                                    .map(|input| {
-                                        self.utxos.get(&input.prev_transaction_output_hash)
-                                        .expect("BUG: Impossible")
-                                        .1.value
-                                    }).sum::<u64>();
-            
-            let all_outputs: u64 = transaction.outputs.iter()
-                                    .map(|output| output.value)
-                                    .sum();
-            let miner_fee = all_inputs - all_outputs;
-            return miner_fee;
-        });
-        return Ok(());                 
+            .and_modify(|(marked, _)| {
+                *marked = false;
+            });
+        }
+    }
+
+    //drop a tx and its descendants from the mempool and
+    //unmark the utxos they spend
+    fn remove_from_mempool(self: &mut Self, hash: &Hash) {
+        for entry in self.mempool.evict(hash) {
+            self.unmark_inputs(&entry.transaction);
+        }
+    }
+
+    //set the reservation flags to exactly the utxos the mempool spends
+    pub fn clear_reservations(self: &mut Self) {
+        for (hash, (marked, _)) in self.utxos.iter_mut() {
+            *marked = self.mempool.spender(hash).is_some();
+        }
+    }
+
+    //pending transactions to save, parents first
+    pub fn saved_mempool(self: &Self) -> SavedMempool {
+        return SavedMempool { transactions: self.mempool.transactions() };
+    }
+
+    /* replace the mempool with saved transactions, validating
+    them again against the current utxos. Expired ones and any no
+    longer valid are dropped. The size cap and rolling min fee
+    stay as they were. Returns how many were restored */
+    pub fn restore_mempool(self: &mut Self, saved: SavedMempool) -> usize {
+        self.mempool.clear();
+        self.clear_reservations();
+        let cutoff = Utc::now() - chrono::Duration::seconds(
+                        crate::MAX_MEMPOOL_TRANSACTION_AGE as i64);
+        let mut restored = 0;
+        for (added_at, transaction) in saved.transactions {
+            if added_at >= cutoff && self.add_to_mempool_at(transaction, added_at).is_ok() {
+                restored += 1;
+            }
+        }
+        return restored;
     }
 
     //remove tx older than MAX_MEMPOOL_TX_AGE
     pub fn cleanup_mempool(self: &mut Self) {
-        let now = Utc::now();
-        let mut utxos_hashes_to_unmark: Vec<Hash> = Vec::new();
-        self.mempool.retain(|(timestamp, tx)| {
-            if now - *timestamp > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64) {
-                //push all utxos to unmark to the vector to be unmarked later
-                utxos_hashes_to_unmark.extend(tx.inputs.iter().map(
-                    |input| {
-                        input.prev_transaction_output_hash
-                    },
-                ));
-                false
-            } else {
-                true
-            }
-        });
+        let cutoff = Utc::now() - chrono::Duration::seconds(
+                        crate::MAX_MEMPOOL_TRANSACTION_AGE as i64);
+        for hash in self.mempool.added_before(cutoff) {
+            self.remove_from_mempool(&hash);
+        }
+    }
 
-        //unmark all the utxos
-        for hash in utxos_hashes_to_unmark {
-            self.utxos.entry(hash).and_modify(|(marked, _)| {
-                *marked = false;
-            },);
+    /* block paying the miner the reward plus the fees of the best
+    paying mempool transactions that fit in MAX_BLOCK_SIZE. Still
+    needs to be mined */
+    pub fn block_template(self: &Self, miner: &PublicKey) -> Block {
+        let prev_block_hash = self.last_block_hash();
+        let mut coinbase = Transactions::new(vec![], vec![TransactionsOutput {
+            value: u64::MAX,
+            unique_id: Uuid::new_v4(),
+            lock: OutputLock::PubKeyHash(miner.pubkey_hash()),
+        }]);
+        let mut header = BlockHeader::new(Utc::now(), 0, prev_block_hash,
+                                          MerkleRoot::calculate(&[coinbase.clone()]),
+                                          self.target);
+
+        //room left next to the header and coinbase, keeping some
+        //for the transaction count to grow
+        let base_size = Block::new(header.clone(), vec![coinbase.clone()]).size() as u64;
+        let max_size = crate::MAX_BLOCK_SIZE.saturating_sub(base_size + 8);
+        let selected = self.mempool.select(max_size);
+
+        let fees: u64 = selected.iter().map(|entry| entry.fee).sum();
+        coinbase.outputs[0].value = Block::calculate_block_reward(self.block_height()) + fees;
+        let mut transactions = vec![coinbase];
+        transactions.extend(selected.into_iter().map(|entry| entry.transaction.clone()));
+        header.merkle_root = MerkleRoot::calculate(&transactions);
+        return Block::new(header, transactions);
+    }
+}
+
+//utxo set of a chain kept in a block store, as of height blocks
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ChainState {
+    height: u64,
+    //hash of the last of those blocks
+    tip: Hash,
+    target: U256,
+    utxos: HashMap<Hash, (bool, TransactionsOutput)>,
+    fee_history: VecDeque<u64>,
+}
+
+impl Default for ChainState {
+
+    fn default() -> Self {
+        return ChainState {
+            height: 0,
+            tip: Hash::zero(),
+            target: crate::MIN_TARGET,
+            utxos: HashMap::new(),
+            fee_history: VecDeque::new(),
+        };
+    }
+}
+
+impl ChainState {
+
+    //apply delta, returning the delta that takes it back
+    fn apply(self: &mut Self, delta: ChainDelta) -> ChainDelta {
+        for (hash, _) in &delta.removed {
+            self.utxos.remove(hash);
         }
+        for (hash, output) in &delta.added {
+            self.utxos.insert(*hash, (false, output.clone()));
+        }
+        return ChainDelta {
+            height: std::mem::replace(&mut self.height, delta.height),
+            tip: std::mem::replace(&mut self.tip, delta.tip),
+            target: std::mem::replace(&mut self.target, delta.target),
+            fee_history: std::mem::replace(&mut self.fee_history, delta.fee_history),
+            removed: delta.added,
+            added: delta.removed,
+        };
     }
 }
 
+/* what connecting or disconnecting a block changed in the chain
+state: the outputs taken out of the utxo set and put in, and the
+height, target and fee history after it */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ChainDelta {
+    height: u64,
+    tip: Hash,
+    target: U256,
+    fee_history: VecDeque<u64>,
+    removed: Vec<(Hash, TransactionsOutput)>,
+    added: Vec<(Hash, TransactionsOutput)>,
+}
+
+//outputs a block adds to the utxo set, those it spends itself
+//never get there
+fn created_outputs(block: &Block) -> Vec<(Hash, TransactionsOutput)> {
+    let spent: HashSet<Hash> = block.transactions.iter()
+                    .flat_map(|transaction| &transaction.inputs)
+                    .map(|input| input.prev_transaction_output_hash)
+                    .collect();
+    return block.transactions.iter()
+                    .flat_map(|transaction| &transaction.outputs)
+                    .map(|output| (output.hash(), output.clone()))
+                    .filter(|(hash, _)| !spent.contains(hash))
+                    .collect();
+}
+
 impl Saveable for BlockChain {
 
     fn load<I: Read>(reader: I) -> IOResult<Self> {
@@ -323,4 +1232,182 @@ impl Saveable for BlockChain {
             "Failed to serialize Blockchain")
         })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::types::TransactionsInput;
+    use crate::util::TestDir;
+
+    //a block after prev paying value to miner, mined at MIN_TARGET
+    fn mine_block(prev: Hash, value: u64, timestamp: DateTime<Utc>, miner: &PublicKey) -> Block {
+        let transactions = vec![Transactions::new(vec![], vec![TransactionsOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            lock: OutputLock::PubKeyHash(miner.pubkey_hash()),
+        }])];
+        let mut header = BlockHeader::new(timestamp, 0, prev, MerkleRoot::calculate(&transactions),
+                                          crate::MIN_TARGET);
+        while !header.mine(1_000_000) {}
+        return Block::new(header, transactions);
+    }
+
+    //count blocks after prev from height on, a second apart
+    fn mine_chain(prev: Hash, height: u64, count: u64, miner: &PublicKey) -> Vec<Block> {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut blocks: Vec<Block> = Vec::new();
+        for height in height..height + count {
+            let prev = blocks.last().map(|block| block.hash()).unwrap_or(prev);
+            let timestamp = start + chrono::Duration::seconds(height as i64);
+            blocks.push(mine_block(prev, Block::calculate_block_reward(height), timestamp, miner));
+        }
+        return blocks;
+    }
+
+    fn utxo_hashes(blockchain: &BlockChain) -> HashSet<Hash> {
+        return blockchain.utxos().keys().copied().collect();
+    }
+
+    fn coinbase_outputs(blocks: &[Block]) -> HashSet<Hash> {
+        return blocks.iter().map(|block| block.transactions[0].outputs[0].hash()).collect();
+    }
+
+    //a tx spending output with a signature from signer, revealing
+    //pubkey, paying value to pubkey
+    fn spend(output: Hash, signer: &PrivateKey, pubkey: &PublicKey, value: u64) -> Transactions {
+        return Transactions::new(vec![TransactionsInput {
+            prev_transaction_output_hash: output,
+            signature: Signature::sign_output(&output, signer),
+            pubkey: Some(pubkey.clone()),
+        }], vec![TransactionsOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            lock: OutputLock::PubKeyHash(pubkey.pubkey_hash()),
+        }]);
+    }
+
+    #[test]
+    fn reorganize_switches_utxos_and_index_and_survives_reopen() {
+        let dir = TestDir::new("chain-reorg");
+        let ours = PrivateKey::new_key().public_key();
+        let theirs = PrivateKey::new_key().public_key();
+        let mut blockchain = BlockChain::open(dir.path()).unwrap();
+        blockchain.enable_index().unwrap();
+        let original = mine_chain(Hash::zero(), 0, 3, &ours);
+        for block in original.clone() {
+            blockchain.add_block(block).unwrap();
+        }
+
+        let fork = mine_chain(original[0].hash(), 1, 3, &theirs);
+        blockchain.reorganize(1, fork.clone()).unwrap();
+        let mut expected = coinbase_outputs(&original[..1]);
+        expected.extend(coinbase_outputs(&fork));
+        let replaced = original[1].transactions[0].hash();
+        let check = |blockchain: &BlockChain| {
+            assert_eq!(blockchain.block_height(), 4);
+            assert_eq!(blockchain.last_block_hash(), fork[2].hash());
+            assert_eq!(utxo_hashes(blockchain), expected);
+            assert_eq!(blockchain.history(&ours).unwrap().len(), 1);
+            assert_eq!(blockchain.history(&theirs).unwrap().len(), 3);
+            assert!(blockchain.transaction(&replaced).is_none());
+            assert!(blockchain.transaction(&fork[2].transactions[0].hash()).is_some());
+        };
+        check(&blockchain);
+
+        drop(blockchain);
+        let mut blockchain = BlockChain::open(dir.path()).unwrap();
+        blockchain.enable_index().unwrap();
+        check(&blockchain);
+    }
+
+    #[test]
+    fn failed_reorganize_restores_utxos_and_index() {
+        let dir = TestDir::new("chain-failed-reorg");
+        let ours = PrivateKey::new_key().public_key();
+        let theirs = PrivateKey::new_key().public_key();
+        let mut blockchain = BlockChain::open(dir.path()).unwrap();
+        blockchain.enable_index().unwrap();
+        let original = mine_chain(Hash::zero(), 0, 3, &ours);
+        for block in original.clone() {
+            blockchain.add_block(block).unwrap();
+        }
+        let utxos = utxo_hashes(&blockchain);
+
+        //more work than the blocks it replaces, but the last one
+        //pays itself too much
+        let mut fork = mine_chain(original[0].hash(), 1, 2, &theirs);
+        let timestamp = fork[1].header.timestamp + chrono::Duration::seconds(1);
+        let value = Block::calculate_block_reward(3) + 1;
+        fork.push(mine_block(fork[1].hash(), value, timestamp, &theirs));
+        assert!(blockchain.reorganize(1, fork).is_err());
+
+        let check = |blockchain: &BlockChain| {
+            assert_eq!(blockchain.block_height(), 3);
+            assert_eq!(blockchain.last_block_hash(), original[2].hash());
+            assert_eq!(utxo_hashes(blockchain), utxos);
+            assert_eq!(blockchain.history(&ours).unwrap().len(), 3);
+            assert!(blockchain.history(&theirs).unwrap().is_empty());
+            assert!(blockchain.transaction(&original[1].transactions[0].hash()).is_some());
+        };
+        check(&blockchain);
+
+        drop(blockchain);
+        let mut blockchain = BlockChain::open(dir.path()).unwrap();
+        blockchain.enable_index().unwrap();
+        check(&blockchain);
+    }
+
+    #[test]
+    fn replacement_needs_a_valid_signature() {
+        let dir = TestDir::new("chain-mempool-signature");
+        let owner = PrivateKey::new_key();
+        let thief = PrivateKey::new_key();
+        let pubkey = owner.public_key();
+        let mut blockchain = BlockChain::open(dir.path()).unwrap();
+        let blocks = mine_chain(Hash::zero(), 0, 1, &pubkey);
+        blockchain.add_block(blocks[0].clone()).unwrap();
+        let output = blocks[0].transactions[0].outputs[0].hash();
+        let reward = Block::calculate_block_reward(0);
+
+        let original = spend(output, &owner, &pubkey, reward - 10_000);
+        blockchain.add_to_mempool(original.clone()).unwrap();
+
+        //pays far more, but is not signed by the owner of the output
+        let forged = spend(output, &thief, &pubkey, reward - 1_000_000);
+        let result = blockchain.add_to_mempool(forged);
+        assert!(matches!(result, Err(BtcError::InvalidSignature)));
+        let unrevealed = spend(output, &thief, &thief.public_key(), reward - 1_000_000);
+        let result = blockchain.add_to_mempool(unrevealed);
+        assert!(matches!(result, Err(BtcError::InvalidTransaction)));
+        assert_eq!(blockchain.mempool().len(), 1);
+        assert!(blockchain.mempool().contains(&original.hash()));
+
+        let replacement = spend(output, &owner, &pubkey, reward - 1_000_000);
+        blockchain.add_to_mempool(replacement.clone()).unwrap();
+        assert!(blockchain.mempool().contains(&replacement.hash()));
+        assert!(!blockchain.mempool().contains(&original.hash()));
+    }
+
+    #[test]
+    fn overflowing_outputs_are_refused() {
+        let dir = TestDir::new("chain-mempool-overflow");
+        let owner = PrivateKey::new_key();
+        let pubkey = owner.public_key();
+        let mut blockchain = BlockChain::open(dir.path()).unwrap();
+        let blocks = mine_chain(Hash::zero(), 0, 1, &pubkey);
+        blockchain.add_block(blocks[0].clone()).unwrap();
+        let output = blocks[0].transactions[0].outputs[0].hash();
+
+        //two outputs that wrap around to less than the input
+        let mut transaction = spend(output, &owner, &pubkey, u64::MAX);
+        transaction.outputs.push(TransactionsOutput {
+            value: 2,
+            unique_id: Uuid::new_v4(),
+            lock: OutputLock::PubKeyHash(pubkey.pubkey_hash()),
+        });
+        let result = blockchain.add_to_mempool(transaction);
+        assert!(matches!(result, Err(BtcError::InvalidTransaction)));
+        assert_eq!(blockchain.mempool().len(), 0);
+    }
+}
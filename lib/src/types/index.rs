@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::crypto::{PubKeyHash, PublicKey};
+use crate::sha256::Hash;
+
+use super::{Block, TransactionsOutput};
+
+//where a confirmed transaction sits in the chain
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxLocation {
+    pub height: u64,
+    pub position: u32,
+}
+
+//an output paying some key, where it was created and spent
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutputRecord {
+    pub hash: Hash,
+    pub output: TransactionsOutput,
+    pub created: TxLocation,
+    pub spent: Option<TxLocation>,
+}
+
+/* optional lookups over the confirmed chain, txid to location
+and pubkey hash to every output paying it. Kept in step with the
+chain as blocks are connected and disconnected */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ChainIndex {
+    transactions: HashMap<Hash, TxLocation>,
+    outputs: HashMap<Hash, OutputRecord>,
+    //output hashes in the order they were created
+    addresses: HashMap<PubKeyHash, Vec<Hash>>,
+}
+
+impl ChainIndex {
+
+    pub fn new() -> Self {
+        return ChainIndex::default();
+    }
+
+    pub fn transaction(self: &Self, txid: &Hash) -> Option<TxLocation> {
+        return self.transactions.get(txid).copied();
+    }
+
+    pub fn output(self: &Self, hash: &Hash) -> Option<&OutputRecord> {
+        return self.outputs.get(hash);
+    }
+
+    //every output ever paid to pubkey, oldest first
+    pub fn history(self: &Self, pubkey: &PublicKey) -> Vec<&OutputRecord> {
+        let Some(hashes) = self.addresses.get(&pubkey.pubkey_hash()) else {
+            return Vec::new();
+        };
+        return hashes.iter()
+                .filter_map(|hash| self.outputs.get(hash))
+                .filter(|record| record.output.lock.is_owned_by(pubkey))
+                .collect();
+    }
+
+    //add a block at height
+    pub fn connect(self: &mut Self, block: &Block, height: u64) {
+        self.connect_transactions(&IndexedTransaction::of_block(block), height);
+    }
+
+    //take back connect for the last block
+    pub fn disconnect(self: &mut Self, block: &Block) {
+        self.disconnect_transactions(&IndexedTransaction::of_block(block));
+    }
+
+    pub(crate) fn apply(self: &mut Self, delta: &IndexDelta) {
+        match delta.connect {
+            true => self.connect_transactions(&delta.transactions, delta.height),
+            false => self.disconnect_transactions(&delta.transactions),
+        }
+    }
+
+    pub(crate) fn revert(self: &mut Self, delta: &IndexDelta) {
+        match delta.connect {
+            true => self.disconnect_transactions(&delta.transactions),
+            false => self.connect_transactions(&delta.transactions, delta.height),
+        }
+    }
+
+    fn connect_transactions(self: &mut Self, transactions: &[IndexedTransaction], height: u64) {
+        for (position, transaction) in transactions.iter().enumerate() {
+            let location = TxLocation { height, position: position as u32 };
+            self.transactions.insert(transaction.hash, location);
+            for input in &transaction.inputs {
+                if let Some(record) = self.outputs.get_mut(input) {
+                    record.spent = Some(location);
+                }
+            }
+            for output in &transaction.outputs {
+                let hash = output.hash();
+                self.addresses.entry(output.lock.pubkey_hash()).or_default().push(hash);
+                self.outputs.insert(hash, OutputRecord {
+                    hash,
+                    output: output.clone(),
+                    created: location,
+                    spent: None,
+                });
+            }
+        }
+    }
+
+    fn disconnect_transactions(self: &mut Self, transactions: &[IndexedTransaction]) {
+        for transaction in transactions.iter().rev() {
+            for output in transaction.outputs.iter().rev() {
+                let hash = output.hash();
+                self.outputs.remove(&hash);
+                let pubkey_hash = output.lock.pubkey_hash();
+                if let Some(hashes) = self.addresses.get_mut(&pubkey_hash) {
+                    if let Some(idx) = hashes.iter().rposition(|created| *created == hash) {
+                        hashes.remove(idx);
+                    }
+                    if hashes.is_empty() {
+                        self.addresses.remove(&pubkey_hash);
+                    }
+                }
+            }
+            for input in &transaction.inputs {
+                if let Some(record) = self.outputs.get_mut(input) {
+                    record.spent = None;
+                }
+            }
+            self.transactions.remove(&transaction.hash);
+        }
+    }
+}
+
+//what the index needs of a transaction, to connect or disconnect
+//it without the block
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct IndexedTransaction {
+    hash: Hash,
+    inputs: Vec<Hash>,
+    outputs: Vec<TransactionsOutput>,
+}
+
+impl IndexedTransaction {
+
+    pub(crate) fn of_block(block: &Block) -> Vec<Self> {
+        return block.transactions.iter().map(|transaction| IndexedTransaction {
+            hash: transaction.hash(),
+            inputs: transaction.inputs.iter()
+                        .map(|input| input.prev_transaction_output_hash)
+                        .collect(),
+            outputs: transaction.outputs.clone(),
+        }).collect();
+    }
+}
+
+/* the block at height connected to or disconnected from the
+index, journaled so the index on disk follows the chain without
+being rewritten. tip is the last block of the chain after it */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct IndexDelta {
+    pub(crate) connect: bool,
+    pub(crate) height: u64,
+    pub(crate) tip: Hash,
+    pub(crate) transactions: Vec<IndexedTransaction>,
+}
+
+impl IndexDelta {
+
+    //number of blocks in the chain after it
+    pub(crate) fn chain_height(self: &Self) -> u64 {
+        return match self.connect {
+            true => self.height + 1,
+            false => self.height,
+        };
+    }
+}
+
+//the index as saved, for a chain of height blocks ending in tip
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SavedIndex {
+    pub(crate) height: u64,
+    pub(crate) tip: Hash,
+    pub(crate) index: ChainIndex,
+}
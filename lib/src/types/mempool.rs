@@ -0,0 +1,707 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read,
+    Result as IOResult, Write};
+
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use crate::util::Saveable;
+
+use super::{Transactions, TransactionsOutput};
+
+//fee paid per byte, compared exactly without rounding
+#[derive(Clone, Copy, Debug)]
+pub struct FeeRate {
+    pub fee: u64,
+    pub size: u64,
+}
+
+impl FeeRate {
+
+    pub fn new(fee: u64, size: u64) -> Self {
+        return FeeRate { fee, size: size.max(1) };
+    }
+
+    //rounded down to whole units per byte
+    pub fn per_byte(self: &Self) -> u64 {
+        return self.fee / self.size;
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lhs = self.fee as u128 * other.size as u128;
+        let rhs = other.fee as u128 * self.size as u128;
+        return lhs.cmp(&rhs);
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for FeeRate {}
+
+#[derive(Clone, Debug)]
+pub struct MempoolEntry {
+    pub transaction: Transactions,
+    pub added_at: DateTime<Utc>,
+    pub fee: u64,
+    //serialized size in bytes
+    pub size: u64,
+    //pending transactions this one spends outputs of
+    pub parents: HashSet<Hash>,
+    //pending transactions spending outputs of this one
+    pub children: HashSet<Hash>,
+    //fee and size of this one with all its descendants
+    pub descendant_fee: u64,
+    pub descendant_size: u64,
+}
+
+impl MempoolEntry {
+
+    pub fn fee_rate(self: &Self) -> FeeRate {
+        return FeeRate::new(self.fee, self.size);
+    }
+
+    /*fee rate a transaction is kept for, the higher of its own and
+    the one of it with all its descendants. A high paying child keeps
+    its parent in, a low paying one never gets it evicted*/
+    pub fn eviction_fee_rate(self: &Self) -> FeeRate {
+        return FeeRate::new(self.descendant_fee, self.descendant_size).max(self.fee_rate());
+    }
+}
+
+/* pending transactions indexed by hash and ordered by fee rate,
+so the best paying ones are found without sorting on every insert.
+Transactions may spend outputs of other pending transactions, the
+parent and child links between them are kept on the entries.
+Bounded to max_size bytes, the lowest paying packages are evicted
+past that and the min fee rate rises to keep them out. Entries
+keep totals with their descendants, updated as transactions come
+and go, so the next package to evict is always at hand */
+#[derive(Clone, Debug)]
+pub struct Mempool {
+    entries: HashMap<Hash, MempoolEntry>,
+    by_fee_rate: BTreeSet<(FeeRate, Hash)>,
+    by_eviction_fee_rate: BTreeSet<(FeeRate, Hash)>,
+    //output hash to the pending transaction spending it
+    spends: HashMap<Hash, Hash>,
+    //outputs created by pending transactions
+    outputs: HashMap<Hash, (Hash, TransactionsOutput)>,
+    //sum of entry sizes
+    size: u64,
+    max_size: u64,
+    //fee rate per byte set by the last eviction and when
+    rolling_min_fee_rate: u64,
+    rolling_updated_at: DateTime<Utc>,
+}
+
+impl Default for Mempool {
+
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl Mempool {
+
+    pub fn new() -> Self {
+        return Mempool {
+            entries: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            by_eviction_fee_rate: BTreeSet::new(),
+            spends: HashMap::new(),
+            outputs: HashMap::new(),
+            size: 0,
+            max_size: crate::MAX_MEMPOOL_SIZE,
+            rolling_min_fee_rate: 0,
+            rolling_updated_at: Utc::now(),
+        };
+    }
+
+    //total serialized size of the pending transactions
+    pub fn size(self: &Self) -> u64 {
+        return self.size;
+    }
+
+    pub fn max_size(self: &Self) -> u64 {
+        return self.max_size;
+    }
+
+    pub fn set_max_size(self: &mut Self, max_size: u64) {
+        self.max_size = max_size;
+    }
+
+    //drop every transaction, keeping the size cap and rolling min fee
+    pub fn clear(self: &mut Self) {
+        self.entries.clear();
+        self.by_fee_rate.clear();
+        self.by_eviction_fee_rate.clear();
+        self.spends.clear();
+        self.outputs.clear();
+        self.size = 0;
+    }
+
+    //rolling min fee rate halved for every ROLLING_FEE_HALFLIFE
+    //since the last eviction
+    fn rolling_min_fee_rate(self: &Self, now: DateTime<Utc>) -> u64 {
+        let elapsed = (now - self.rolling_updated_at).num_seconds().max(0) as f64;
+        let halvings = elapsed / crate::ROLLING_FEE_HALFLIFE as f64;
+        return (self.rolling_min_fee_rate as f64 * 0.5f64.powf(halvings)) as u64;
+    }
+
+    //fee per byte a transaction needs to get in
+    pub fn min_fee_rate(self: &Self) -> u64 {
+        return self.rolling_min_fee_rate(Utc::now()).max(crate::MIN_RELAY_FEE_RATE);
+    }
+
+    pub fn len(self: &Self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    pub fn contains(self: &Self, hash: &Hash) -> bool {
+        return self.entries.contains_key(hash);
+    }
+
+    pub fn get(self: &Self, hash: &Hash) -> Option<&MempoolEntry> {
+        return self.entries.get(hash);
+    }
+
+    //highest fee rate first
+    pub fn iter(self: &Self) -> impl Iterator<Item = &MempoolEntry> {
+        return self.by_fee_rate.iter().rev().map(|(_, hash)| &self.entries[hash]);
+    }
+
+    //pending transaction spending the output, if any
+    pub fn spender(self: &Self, output_hash: &Hash) -> Option<Hash> {
+        return self.spends.get(output_hash).copied();
+    }
+
+    //output created by a pending transaction
+    pub fn output(self: &Self, output_hash: &Hash) -> Option<&TransactionsOutput> {
+        return self.outputs.get(output_hash).map(|(_, output)| output);
+    }
+
+    //all outputs created by pending transactions. Bool determines
+    //if a pending transaction already spends it
+    pub fn outputs(self: &Self) -> impl Iterator<Item = (&TransactionsOutput, bool)> {
+        return self.outputs.iter().map(|(hash, (_, output))| {
+            (output, self.spends.contains_key(hash))
+        });
+    }
+
+    //follow parent or child links from hash, not including it
+    fn walk(self: &Self, hash: &Hash,
+            links: fn(&MempoolEntry) -> &HashSet<Hash>) -> HashSet<Hash> {
+        let mut found = HashSet::new();
+        let mut stack = vec![*hash];
+        while let Some(hash) = stack.pop() {
+            if let Some(entry) = self.entries.get(&hash) {
+                for linked in links(entry) {
+                    if found.insert(*linked) {
+                        stack.push(*linked);
+                    }
+                }
+            }
+        }
+        return found;
+    }
+
+    pub fn ancestors(self: &Self, hash: &Hash) -> HashSet<Hash> {
+        return self.walk(hash, |entry| &entry.parents);
+    }
+
+    pub fn descendants(self: &Self, hash: &Hash) -> HashSet<Hash> {
+        return self.walk(hash, |entry| &entry.children);
+    }
+
+    //pending transactions the transaction would spend from
+    pub fn parents_of(self: &Self, transaction: &Transactions) -> HashSet<Hash> {
+        return transaction.inputs.iter()
+                .filter_map(|input| self.outputs.get(&input.prev_transaction_output_hash))
+                .map(|(parent, _)| *parent)
+                .collect();
+    }
+
+    //all pending transactions the transaction would depend on
+    pub fn ancestors_of(self: &Self, transaction: &Transactions) -> HashSet<Hash> {
+        let mut ancestors = HashSet::new();
+        for parent in self.parents_of(transaction) {
+            ancestors.extend(self.ancestors(&parent));
+            ancestors.insert(parent);
+        }
+        return ancestors;
+    }
+
+    /* fails if the transaction would get more than
+    MAX_MEMPOOL_ANCESTORS ancestors or give any of them more than
+    MAX_MEMPOOL_DESCENDANTS descendants */
+    pub fn check_limits(self: &Self, transaction: &Transactions) -> Result<()> {
+        let ancestors = self.ancestors_of(transaction);
+        if ancestors.len() + 1 > crate::MAX_MEMPOOL_ANCESTORS {
+            return Err(BtcError::MempoolChainLimit);
+        }
+        for ancestor in &ancestors {
+            if self.descendants(ancestor).len() + 2 > crate::MAX_MEMPOOL_DESCENDANTS {
+                return Err(BtcError::MempoolChainLimit);
+            }
+        }
+        return Ok(());
+    }
+
+    /* a transaction spending the same outputs as the conflicts
+    replaces them only if it pays a strictly higher fee than all the
+    transactions it evicts and a strictly higher fee rate than each
+    conflict, evicting at most MAX_REPLACEMENT_EVICTIONS */
+    pub fn check_replacement(self: &Self, conflicts: &HashSet<Hash>,
+                             fee: u64, size: u64) -> Result<()> {
+        let mut evicted = conflicts.clone();
+        for conflict in conflicts {
+            evicted.extend(self.descendants(conflict));
+        }
+        if evicted.len() > crate::MAX_REPLACEMENT_EVICTIONS {
+            return Err(BtcError::TooManyReplacements);
+        }
+        let evicted_fee: u64 = evicted.iter()
+                                .map(|hash| self.entries[hash].fee)
+                                .sum();
+        //the extra fee also has to pay for relaying the replacement
+        if fee <= evicted_fee
+            || fee - evicted_fee < crate::MIN_RELAY_FEE_RATE * size {
+            return Err(BtcError::InsufficientReplacementFee);
+        }
+        let fee_rate = FeeRate::new(fee, size);
+        if conflicts.iter().any(|conflict| fee_rate <= self.entries[conflict].fee_rate()) {
+            return Err(BtcError::InsufficientReplacementFee);
+        }
+        return Ok(());
+    }
+
+    //add a transaction whose inputs are already validated
+    pub fn insert(self: &mut Self, transaction: Transactions,
+                  fee: u64, added_at: DateTime<Utc>) -> Result<Hash> {
+        self.check_limits(&transaction)?;
+
+        let hash = transaction.hash();
+        let size = transaction.size() as u64;
+        for ancestor in self.ancestors_of(&transaction) {
+            self.adjust_descendant_totals(&ancestor, |entry| {
+                entry.descendant_fee += fee;
+                entry.descendant_size += size;
+            });
+        }
+        let parents = self.parents_of(&transaction);
+        for parent in &parents {
+            self.entries.get_mut(parent)
+                .expect("BUG: Impossible")
+                .children.insert(hash);
+        }
+        for input in &transaction.inputs {
+            self.spends.insert(input.prev_transaction_output_hash, hash);
+        }
+        for output in &transaction.outputs {
+            self.outputs.insert(output.hash(), (hash, output.clone()));
+        }
+        let entry = MempoolEntry {
+            size,
+            transaction,
+            added_at,
+            fee,
+            parents,
+            children: HashSet::new(),
+            descendant_fee: fee,
+            descendant_size: size,
+        };
+        self.size += entry.size;
+        self.by_fee_rate.insert((entry.fee_rate(), hash));
+        self.by_eviction_fee_rate.insert((entry.eviction_fee_rate(), hash));
+        self.entries.insert(hash, entry);
+        return Ok(hash);
+    }
+
+    //change an entry's totals with its descendants, keeping its
+    //place in the eviction order
+    fn adjust_descendant_totals(self: &mut Self, hash: &Hash, adjust: impl FnOnce(&mut MempoolEntry)) {
+        let entry = self.entries.get_mut(hash).expect("BUG: Impossible");
+        self.by_eviction_fee_rate.remove(&(entry.eviction_fee_rate(), *hash));
+        adjust(entry);
+        self.by_eviction_fee_rate.insert((entry.eviction_fee_rate(), *hash));
+    }
+
+    /* remove entries, taking each out of the totals of its
+    ancestors that stay. Ancestors are found before anything is
+    unlinked, so a package can be removed in any order */
+    fn remove_entries(self: &mut Self, hashes: &[Hash]) -> Vec<MempoolEntry> {
+        let removing: HashSet<Hash> = hashes.iter().copied().collect();
+        let mut adjustments = Vec::new();
+        for hash in hashes {
+            if let Some(entry) = self.entries.get(hash) {
+                for ancestor in self.ancestors(hash) {
+                    if !removing.contains(&ancestor) {
+                        adjustments.push((ancestor, entry.fee, entry.size));
+                    }
+                }
+            }
+        }
+        for (ancestor, fee, size) in adjustments {
+            self.adjust_descendant_totals(&ancestor, |entry| {
+                entry.descendant_fee -= fee;
+                entry.descendant_size -= size;
+            });
+        }
+        return hashes.iter().filter_map(|hash| self.remove_entry(hash)).collect();
+    }
+
+    //unlink a single entry, its children are left in place
+    fn remove_entry(self: &mut Self, hash: &Hash) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(hash)?;
+        self.size -= entry.size;
+        self.by_fee_rate.remove(&(entry.fee_rate(), *hash));
+        self.by_eviction_fee_rate.remove(&(entry.eviction_fee_rate(), *hash));
+        for input in &entry.transaction.inputs {
+            self.spends.remove(&input.prev_transaction_output_hash);
+        }
+        for output in &entry.transaction.outputs {
+            self.outputs.remove(&output.hash());
+        }
+        for parent in &entry.parents {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.remove(hash);
+            }
+        }
+        for child in &entry.children {
+            if let Some(child) = self.entries.get_mut(child) {
+                child.parents.remove(hash);
+            }
+        }
+        return Some(entry);
+    }
+
+    //transaction was mined, its children now spend confirmed outputs
+    pub fn confirm(self: &mut Self, hash: &Hash) -> Option<MempoolEntry> {
+        return self.remove_entries(&[*hash]).pop();
+    }
+
+    //remove a transaction together with everything spending from it
+    pub fn evict(self: &mut Self, hash: &Hash) -> Vec<MempoolEntry> {
+        if !self.entries.contains_key(hash) {
+            return Vec::new();
+        }
+        let mut package = vec![*hash];
+        package.extend(self.descendants(hash));
+        return self.remove_entries(&package);
+    }
+
+    /* add a validated transaction in place of the conflicts it
+    replaces, then evict the lowest paying packages if the mempool
+    is over max_size. All or nothing: if the transaction can not go
+    in or is evicted itself, everything taken out is put back and
+    the mempool is left as it was. Returns what was evicted */
+    pub fn add(self: &mut Self, transaction: Transactions, fee: u64,
+               added_at: DateTime<Utc>, conflicts: &HashSet<Hash>) -> Result<Vec<MempoolEntry>> {
+        let rolling = (self.rolling_min_fee_rate, self.rolling_updated_at);
+        let mut evicted = Vec::new();
+        for conflict in conflicts {
+            evicted.extend(self.evict(conflict));
+        }
+        let hash = match self.insert(transaction, fee, added_at) {
+            Ok(hash) => hash,
+            Err(e) => {
+                self.restore(evicted);
+                return Err(e);
+            }
+        };
+        let trimmed = self.trim();
+        if self.contains(&hash) {
+            evicted.extend(trimmed);
+            return Ok(evicted);
+        }
+        evicted.extend(trimmed.into_iter().filter(|entry| entry.transaction.hash() != hash));
+        self.restore(evicted);
+        (self.rolling_min_fee_rate, self.rolling_updated_at) = rolling;
+        return Err(BtcError::InsufficientFee);
+    }
+
+    //put evicted entries back, each after the ones it spends from
+    fn restore(self: &mut Self, mut pending: Vec<MempoolEntry>) {
+        while !pending.is_empty() {
+            let created: HashSet<Hash> = pending.iter()
+                                .flat_map(|entry| &entry.transaction.outputs)
+                                .map(|output| output.hash())
+                                .collect();
+            let (ready, rest): (Vec<MempoolEntry>, Vec<MempoolEntry>) = pending.into_iter()
+                .partition(|entry| !entry.transaction.inputs.iter().any(|input| {
+                    created.contains(&input.prev_transaction_output_hash)
+                }));
+            if ready.is_empty() {
+                return;
+            }
+            for entry in ready {
+                //they were all in before, so they still fit the limits
+                let _ = self.insert(entry.transaction, entry.fee, entry.added_at);
+            }
+            pending = rest;
+        }
+    }
+
+    /* evict the lowest paying packages until the mempool fits in
+    max_size, raising the rolling min fee rate above the best of them */
+    pub fn trim(self: &mut Self) -> Vec<MempoolEntry> {
+        let mut evicted = Vec::new();
+        while self.size > self.max_size {
+            let Some(&(fee_rate, hash)) = self.by_eviction_fee_rate.first() else {
+                break;
+            };
+            let now = Utc::now();
+            let rolling = self.rolling_min_fee_rate(now)
+                            .max(fee_rate.per_byte() + crate::MIN_RELAY_FEE_RATE);
+            self.rolling_min_fee_rate = rolling;
+            self.rolling_updated_at = now;
+            evicted.extend(self.evict(&hash));
+        }
+        return evicted;
+    }
+
+    fn with_parents_first(self: &Self, hash: &Hash, visited: &mut HashSet<Hash>,
+                          ordered: &mut Vec<Hash>) {
+        if !visited.insert(*hash) {
+            return;
+        }
+        for parent in &self.entries[hash].parents {
+            self.with_parents_first(parent, visited, ordered);
+        }
+        ordered.push(*hash);
+    }
+
+    //every transaction with the time it was added, parents first
+    pub fn transactions(self: &Self) -> Vec<(DateTime<Utc>, Transactions)> {
+        let mut hashes: Vec<&Hash> = self.entries.keys().collect();
+        hashes.sort_by_key(|hash| self.entries[*hash].added_at);
+        let mut ordered = Vec::new();
+        let mut visited = HashSet::new();
+        for hash in hashes {
+            self.with_parents_first(hash, &mut visited, &mut ordered);
+        }
+        return ordered.iter()
+                .map(|hash| {
+                    let entry = &self.entries[hash];
+                    (entry.added_at, entry.transaction.clone())
+                })
+                .collect();
+    }
+
+    //transactions added before the cutoff
+    pub fn added_before(self: &Self, cutoff: DateTime<Utc>) -> Vec<Hash> {
+        return self.entries.iter()
+                    .filter(|(_, entry)| entry.added_at < cutoff)
+                    .map(|(hash, _)| *hash)
+                    .collect();
+    }
+
+    //hash and its not yet included ancestors, parents first
+    fn package(self: &Self, hash: &Hash, included: &HashSet<Hash>,
+               package: &mut Vec<Hash>) {
+        if included.contains(hash) || package.contains(hash) {
+            return;
+        }
+        for parent in &self.entries[hash].parents {
+            self.package(parent, included, package);
+        }
+        package.push(*hash);
+    }
+
+    fn package_fee_rate(self: &Self, package: &[Hash]) -> FeeRate {
+        let fee = package.iter().map(|hash| self.entries[hash].fee).sum();
+        let size = package.iter().map(|hash| self.entries[hash].size).sum();
+        return FeeRate::new(fee, size);
+    }
+
+    /* best paying transactions fitting in max_size bytes, parents
+    before children. Each transaction is ranked by the fee rate of
+    the package it forms with its ancestors not yet taken, so a child
+    paying a high fee pulls in a low paying parent. Packages are taken
+    greedily, skipping any that no longer fit so smaller ones can still
+    fill the space left */
+    pub fn select(self: &Self, max_size: u64) -> Vec<&MempoolEntry> {
+        let mut selected = Vec::new();
+        let mut included = HashSet::new();
+        let mut size = 0;
+
+        let mut rates: HashMap<Hash, FeeRate> = HashMap::new();
+        let mut queue: BTreeSet<(FeeRate, Hash)> = BTreeSet::new();
+        for hash in self.entries.keys() {
+            let mut package = Vec::new();
+            self.package(hash, &included, &mut package);
+            let rate = self.package_fee_rate(&package);
+            rates.insert(*hash, rate);
+            queue.insert((rate, *hash));
+        }
+
+        while let Some((_, hash)) = queue.pop_last() {
+            let mut package = Vec::new();
+            self.package(&hash, &included, &mut package);
+            let package_size: u64 = package.iter()
+                                        .map(|hash| self.entries[hash].size)
+                                        .sum();
+            if package.is_empty() || size + package_size > max_size {
+                continue;
+            }
+            size += package_size;
+            for hash in &package {
+                included.insert(*hash);
+                selected.push(&self.entries[hash]);
+            }
+
+            //descendants of what was taken now form smaller packages
+            for hash in &package {
+                for descendant in self.descendants(hash) {
+                    if included.contains(&descendant) {
+                        continue;
+                    }
+                    queue.remove(&(rates[&descendant], descendant));
+                    let mut package = Vec::new();
+                    self.package(&descendant, &included, &mut package);
+                    let rate = self.package_fee_rate(&package);
+                    rates.insert(descendant, rate);
+                    queue.insert((rate, descendant));
+                }
+            }
+        }
+        return selected;
+    }
+}
+
+//mempool contents kept in their own file across node restarts
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SavedMempool {
+    pub transactions: Vec<(DateTime<Utc>, Transactions)>,
+}
+
+impl Saveable for SavedMempool {
+
+    fn load<I: Read>(reader: I) -> IOResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to deserialize SavedMempool")
+        })
+    }
+
+    fn save<O: Write>(self: &Self, writer: O) -> IOResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to serialize SavedMempool")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::types::{OutputLock, TransactionsInput};
+
+    //a transaction spending the outputs of hashes into one output
+    fn spending(hashes: &[Hash], value: u64) -> Transactions {
+        let key = PrivateKey::new_key();
+        let inputs = hashes.iter().map(|hash| TransactionsInput {
+            prev_transaction_output_hash: *hash,
+            signature: Signature::sign_output(hash, &key),
+            pubkey: None,
+        }).collect();
+        let output = TransactionsOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            lock: OutputLock::PubKey(key.public_key()),
+        };
+        return Transactions::new(inputs, vec![output]);
+    }
+
+    fn size(transaction: &Transactions) -> u64 {
+        return transaction.size() as u64;
+    }
+
+    #[test]
+    fn replacement_evicts_conflicts_and_descendants() {
+        let mut mempool = Mempool::new();
+        let now = Utc::now();
+        let coin = Hash::hash(&1);
+        let original = spending(&[coin], 100);
+        mempool.insert(original.clone(), 2 * size(&original), now).unwrap();
+        let child = spending(&[original.outputs[0].hash()], 50);
+        mempool.insert(child.clone(), 2 * size(&child), now).unwrap();
+
+        let conflicts = HashSet::from([original.hash()]);
+        let replacement = spending(&[coin], 90);
+        let evicted_fee = 2 * size(&original) + 2 * size(&child);
+        let result = mempool.check_replacement(&conflicts, evicted_fee, size(&replacement));
+        assert!(matches!(result, Err(BtcError::InsufficientReplacementFee)));
+
+        let fee = evicted_fee + 10 * size(&replacement);
+        mempool.check_replacement(&conflicts, fee, size(&replacement)).unwrap();
+        let evicted = mempool.add(replacement.clone(), fee, now, &conflicts).unwrap();
+        assert_eq!(evicted.len(), 2);
+        assert!(!mempool.contains(&original.hash()));
+        assert!(!mempool.contains(&child.hash()));
+        assert_eq!(mempool.spender(&coin), Some(replacement.hash()));
+        assert_eq!(mempool.size(), size(&replacement));
+    }
+
+    #[test]
+    fn replacement_needs_higher_fee_rate_than_each_conflict() {
+        let mut mempool = Mempool::new();
+        let coin = Hash::hash(&1);
+        let original = spending(&[coin], 100);
+        mempool.insert(original.clone(), 10 * size(&original), Utc::now()).unwrap();
+
+        //more fee in total, but spread over many more bytes
+        let replacement = spending(&[coin, Hash::hash(&2), Hash::hash(&3), Hash::hash(&4)], 90);
+        let fee = 10 * size(&original) + size(&replacement);
+        let conflicts = HashSet::from([original.hash()]);
+        let result = mempool.check_replacement(&conflicts, fee, size(&replacement));
+        assert!(matches!(result, Err(BtcError::InsufficientReplacementFee)));
+    }
+
+    #[test]
+    fn trim_evicts_lowest_fee_rate_and_raises_min_fee() {
+        let mut mempool = Mempool::new();
+        let now = Utc::now();
+        let cheap = spending(&[Hash::hash(&1)], 100);
+        let rich = spending(&[Hash::hash(&2)], 100);
+        mempool.insert(cheap.clone(), size(&cheap), now).unwrap();
+        mempool.insert(rich.clone(), 10 * size(&rich), now).unwrap();
+
+        mempool.set_max_size(mempool.size() - 1);
+        let evicted = mempool.trim();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].transaction.hash(), cheap.hash());
+        assert!(mempool.contains(&rich.hash()));
+        assert_eq!(mempool.rolling_min_fee_rate, 1 + crate::MIN_RELAY_FEE_RATE);
+
+        //a transaction that would be trimmed right away is refused
+        //and the mempool is left as it was
+        mempool.set_max_size(mempool.size());
+        let poor = spending(&[Hash::hash(&3)], 100);
+        let result = mempool.add(poor.clone(), 2 * size(&poor), now, &HashSet::new());
+        assert!(matches!(result, Err(BtcError::InsufficientFee)));
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&rich.hash()));
+        assert_eq!(mempool.spender(&Hash::hash(&3)), None);
+    }
+}
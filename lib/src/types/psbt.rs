@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read,
+    Result as IOResult, Write};
+
+use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use crate::util::Saveable;
+
+use super::{Transactions, TransactionsInput, TransactionsOutput, UnsignedTransaction};
+
+/* unsigned transaction together with everything a signer needs:
+the outputs being spent and the signatures collected so far.
+Passed between machines for offline and multi-party signing */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PartiallySignedTransaction {
+    pub unsigned: UnsignedTransaction,
+    //output spent by each input, in input order
+    pub spent_outputs: Vec<TransactionsOutput>,
+    //signatures collected for each input, keyed by signer
+    pub partial_signatures: Vec<Vec<(PublicKey, Signature)>>,
+    //inputs ready for extraction, set by finalize
+    pub final_inputs: Option<Vec<TransactionsInput>>,
+}
+
+impl PartiallySignedTransaction {
+
+    pub fn new(unsigned: UnsignedTransaction,
+               spent_outputs: Vec<TransactionsOutput>) -> Result<Self> {
+        if unsigned.inputs.len() != spent_outputs.len() {
+            return Err(BtcError::InvalidPsbt);
+        }
+        for (hash, output) in unsigned.inputs.iter().zip(&spent_outputs) {
+            if *hash != output.hash() {
+                return Err(BtcError::InvalidPsbt);
+            }
+        }
+        let partial_signatures = vec![Vec::new(); unsigned.inputs.len()];
+        return Ok(PartiallySignedTransaction {
+            unsigned,
+            spent_outputs,
+            partial_signatures,
+            final_inputs: None,
+        });
+    }
+
+    //input value minus output value
+    pub fn fee(self: &Self) -> u64 {
+        let input_value: u64 = self.spent_outputs.iter()
+                                .map(|output| output.value)
+                                .sum();
+        let output_value: u64 = self.unsigned.outputs.iter()
+                                .map(|output| output.value)
+                                .sum();
+        return input_value.saturating_sub(output_value);
+    }
+
+    pub fn add_signature(self: &mut Self, input: usize,
+                         pubkey: PublicKey, signature: Signature) -> Result<()> {
+        let hash = *self.unsigned.inputs.get(input).ok_or(BtcError::InvalidPsbt)?;
+        if !signature.verify(&hash, &pubkey) {
+            return Err(BtcError::InvalidSignature);
+        }
+        let signatures = &mut self.partial_signatures[input];
+        signatures.retain(|(signer, _)| *signer != pubkey);
+        signatures.push((pubkey, signature));
+        return Ok(());
+    }
+
+    //sign every input locked to this key, returns how many were signed
+    pub fn sign(self: &mut Self, private_key: &PrivateKey) -> Result<usize> {
+        let pubkey = private_key.public_key();
+        let mut signed = 0;
+        for idx in 0..self.spent_outputs.len() {
+            if self.spent_outputs[idx].lock.is_owned_by(&pubkey) {
+                let hash = self.unsigned.inputs[idx];
+                let signature = Signature::sign_output(&hash, private_key);
+                self.add_signature(idx, pubkey.clone(), signature)?;
+                signed += 1;
+            }
+        }
+        return Ok(signed);
+    }
+
+    //merge signatures from another copy of the same transaction
+    pub fn combine(self: &mut Self, other: &Self) -> Result<()> {
+        if self.unsigned.hash() != other.unsigned.hash() {
+            return Err(BtcError::InvalidPsbt);
+        }
+        for (idx, signatures) in other.partial_signatures.iter().enumerate() {
+            for (pubkey, signature) in signatures {
+                self.add_signature(idx, pubkey.clone(), signature.clone())?;
+            }
+        }
+        return Ok(());
+    }
+
+    //signature from the key that can spend the input, if collected
+    fn spending_signature(self: &Self, input: usize) -> Option<&(PublicKey, Signature)> {
+        let lock = &self.spent_outputs[input].lock;
+        return self.partial_signatures[input].iter()
+                    .find(|(pubkey, _)| lock.is_owned_by(pubkey));
+    }
+
+    pub fn is_complete(self: &Self) -> bool {
+        return (0..self.spent_outputs.len())
+                .all(|idx| self.spending_signature(idx).is_some());
+    }
+
+    //build the final inputs once every input has its signature
+    pub fn finalize(self: &mut Self) -> Result<()> {
+        let mut inputs = Vec::with_capacity(self.unsigned.inputs.len());
+        for (idx, hash) in self.unsigned.inputs.iter().enumerate() {
+            let (pubkey, signature) = self.spending_signature(idx)
+                                        .ok_or(BtcError::IncompletePsbt)?;
+            inputs.push(TransactionsInput {
+                prev_transaction_output_hash: *hash,
+                signature: signature.clone(),
+                pubkey: Some(pubkey.clone()),
+            });
+        }
+        self.final_inputs = Some(inputs);
+        return Ok(());
+    }
+
+    pub fn extract(self: &Self) -> Result<Transactions> {
+        let inputs = self.final_inputs.clone().ok_or(BtcError::IncompletePsbt)?;
+        return Ok(Transactions::new(inputs, self.unsigned.outputs.clone()));
+    }
+
+    pub fn hash(self: &Self) -> Hash {
+        return self.unsigned.hash();
+    }
+}
+
+impl Saveable for PartiallySignedTransaction {
+    fn load<I: Read>(reader: I) -> IOResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to deserialize PartiallySignedTransaction")
+        })
+    }
+
+    fn save<O: Write>(self: &Self, writer: O) -> IOResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to serialize PartiallySignedTransaction")
+        })
+    }
+}
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read,
+    Result as IOResult, Write};
+
+use crate::U256;
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use crate::util::Saveable;
+
+use super::TransactionsOutput;
+
+/* utxo set of a chain as of its first height blocks, the last of
+them being block_hash. Entries are sorted by output hash so the
+same set always serializes, and so hashes, the same way */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UtxoSnapshot {
+    pub height: u64,
+    pub block_hash: Hash,
+    pub target: U256,
+    pub utxos: Vec<(Hash, TransactionsOutput)>,
+}
+
+impl UtxoSnapshot {
+
+    pub fn new<I>(height: u64, block_hash: Hash, target: U256, utxos: I) -> Self
+    where
+        I: IntoIterator<Item = (Hash, TransactionsOutput)>,
+    {
+        let mut utxos: Vec<(Hash, TransactionsOutput)> = utxos.into_iter().collect();
+        utxos.sort_by_key(|(hash, _)| *hash);
+        return UtxoSnapshot { height, block_hash, target, utxos };
+    }
+
+    //hash over everything in the snapshot, for operators to check
+    //against a known value before trusting it
+    pub fn commitment(self: &Self) -> Hash {
+        return Hash::hash(&(self.height, self.block_hash, self.target, &self.utxos));
+    }
+
+    //entries must be sorted, unique and keyed by their own hash
+    pub fn verify_entries(self: &Self) -> Result<()> {
+        for pair in self.utxos.windows(2) {
+            if pair[0].0 >= pair[1].0 {
+                return Err(BtcError::InvalidSnapshot);
+            }
+        }
+        if self.utxos.iter().any(|(hash, output)| *hash != output.hash()) {
+            return Err(BtcError::InvalidSnapshot);
+        }
+        return Ok(());
+    }
+}
+
+impl Saveable for UtxoSnapshot {
+
+    fn load<I: Read>(reader: I) -> IOResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to deserialize UtxoSnapshot")
+        })
+    }
+
+    fn save<O: Write>(self: &Self, writer: O) -> IOResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to serialize UtxoSnapshot")
+        })
+    }
+}
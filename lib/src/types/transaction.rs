@@ -1,25 +1,90 @@
 use uuid::Uuid;
 use serde::{Deserialize, Serialize};
-use crate::crypto::{PublicKey, Signature};
+use crate::crypto::{Address, PubKeyHash, PublicKey, Signature};
+use crate::network::Network;
 use crate::sha256::Hash;
 use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read,
     Result as IOResult, Write};
 
-use crate::util::Saveable;
+use crate::util::{serialized_size, Saveable};
 
 
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionsInput {
     pub prev_transaction_output_hash: Hash,
-    pub signature: Signature
+    pub signature: Signature,
+    //public key revealed when spending a pubkey hash output
+    #[serde(default)]
+    pub pubkey: Option<PublicKey>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum OutputLock {
+    /// Spendable by a signature from this public key
+    PubKey(PublicKey),
+    /// Spendable by a signature from a public key hashing
+    /// to this value. The key is revealed by the input
+    PubKeyHash(PubKeyHash),
+}
+
+impl OutputLock {
+
+    //key the spending signature must verify against
+    pub fn spending_key<'a>(self: &'a Self,
+                            revealed: Option<&'a PublicKey>) -> Option<&'a PublicKey> {
+        return match self {
+            OutputLock::PubKey(pubkey) => Some(pubkey),
+            OutputLock::PubKeyHash(hash) => {
+                revealed.filter(|pubkey| pubkey.pubkey_hash() == *hash)
+            }
+        };
+    }
+
+    //check if this output can be spent by the given key
+    pub fn is_owned_by(self: &Self, pubkey: &PublicKey) -> bool {
+        return match self {
+            OutputLock::PubKey(key) => key == pubkey,
+            OutputLock::PubKeyHash(hash) => pubkey.pubkey_hash() == *hash,
+        };
+    }
+
+    //hash of the key this output pays, whichever way it is locked
+    pub fn pubkey_hash(self: &Self) -> PubKeyHash {
+        return match self {
+            OutputLock::PubKey(pubkey) => pubkey.pubkey_hash(),
+            OutputLock::PubKeyHash(hash) => *hash,
+        };
+    }
+
+    pub fn address(self: &Self, network: Network) -> Address {
+        return match self {
+            OutputLock::PubKey(pubkey) => pubkey.address(network),
+            OutputLock::PubKeyHash(hash) => Address::new(network, *hash),
+        };
+    }
+}
+
+impl From<Address> for OutputLock {
+
+    fn from(address: Address) -> Self {
+        return OutputLock::PubKeyHash(address.hash);
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionsOutput {
     pub value: u64,
     pub unique_id: Uuid,
-    pub pubkey: PublicKey
+    pub lock: OutputLock
+}
+
+impl TransactionsInput {
+
+    //serialized size in bytes
+    pub fn size(self: &Self) -> usize {
+        return serialized_size(self);
+    }
 }
 
 impl TransactionsOutput {
@@ -27,6 +92,11 @@ impl TransactionsOutput {
     pub fn hash(self: &Self) -> Hash {
         return Hash::hash(self);
     }
+
+    //serialized size in bytes
+    pub fn size(self: &Self) -> usize {
+        return serialized_size(self);
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -47,6 +117,49 @@ impl Transactions {
     pub fn hash(self: &Self) -> Hash {
         return Hash::hash(self);
     }
+
+    //serialized size in bytes, what fee rates are measured against
+    pub fn size(self: &Self) -> usize {
+        return serialized_size(self);
+    }
+}
+
+// transaction built without access to private keys. Each
+// input is the hash of the output it will spend
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UnsignedTransaction {
+    pub inputs: Vec<Hash>,
+    pub outputs: Vec<TransactionsOutput>
+}
+
+impl UnsignedTransaction {
+
+    pub fn new(
+        inputs: Vec<Hash>,
+        outputs: Vec<TransactionsOutput>
+    ) -> Self {
+        return UnsignedTransaction { inputs, outputs };
+    }
+
+    pub fn hash(self: &Self) -> Hash {
+        return Hash::hash(self);
+    }
+}
+
+impl Saveable for UnsignedTransaction {
+    fn load<I: Read>(reader: I) -> IOResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to deserialize UnsignedTransaction")
+        })
+    }
+
+    fn save<O: Write>(self: &Self, writer: O) -> IOResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to serialize UnsignedTransaction")
+        })
+    }
 }
 
 impl Saveable for Transactions {
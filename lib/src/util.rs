@@ -1,10 +1,19 @@
+use crate::error::BtcError;
 use crate::sha256::Hash;
 use crate::types::Transactions;
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write, Result as IOResult};
-use std::fs::File;
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read, Write,
+    Result as IOResult};
+use std::fs::{self, File};
 use std::path::Path;
 
+//saved files start with this, then the format version
+pub const FILE_MAGIC: [u8; 4] = *b"BTCF";
+pub const FILE_FORMAT_VERSION: u16 = 1;
+//magic, version and payload length
+const FILE_HEADER_SIZE: usize = 4 + 2 + 8;
+pub(crate) const FILE_CHECKSUM_SIZE: usize = 4;
+
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct MerkleRoot(Hash);
 impl MerkleRoot {
@@ -31,17 +40,133 @@ impl MerkleRoot {
     }
 }
 
+//size of anything that can be serde Serialized via ciborium
+pub fn serialized_size<T: Serialize>(data: &T) -> usize {
+    let mut serialized: Vec<u8> = Vec::new();
+    ciborium::into_writer(data, &mut serialized).expect("BUG: Impossible");
+    return serialized.len();
+}
+
+//first bytes of the double sha256 of data
+pub(crate) fn checksum(data: &[u8]) -> [u8; FILE_CHECKSUM_SIZE] {
+    let first = hex::decode(sha256::digest(data)).expect("BUG: Impossible");
+    let second = hex::decode(sha256::digest(first.as_slice())).expect("BUG: Impossible");
+    return second[..FILE_CHECKSUM_SIZE].try_into().expect("BUG: Impossible");
+}
+
+fn corrupted(kind: IOErrorKind, error: BtcError) -> IOError {
+    return IOError::new(kind, error);
+}
+
+/* wrap a saved payload as magic, version, payload length,
+payload and checksum of the payload */
+pub fn seal_file(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(FILE_HEADER_SIZE + payload.len() + FILE_CHECKSUM_SIZE);
+    bytes.extend_from_slice(&FILE_MAGIC);
+    bytes.extend_from_slice(&FILE_FORMAT_VERSION.to_be_bytes());
+    bytes.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes.extend_from_slice(&checksum(payload));
+    return bytes;
+}
+
+/* check the header and checksum written by seal_file and return
+the payload. Files saved before the header existed are returned
+whole. Corruption is reported as an IOError holding the matching
+BtcError */
+pub fn open_file(bytes: &[u8]) -> IOResult<&[u8]> {
+    if !bytes.starts_with(&FILE_MAGIC) {
+        //legacy files are bare cbor, which never starts with the magic
+        return Ok(bytes);
+    }
+    if bytes.len() < FILE_HEADER_SIZE {
+        return Err(corrupted(IOErrorKind::UnexpectedEof, BtcError::TruncatedFile));
+    }
+    let version = u16::from_be_bytes(bytes[4..6].try_into().expect("BUG: Impossible"));
+    if version != FILE_FORMAT_VERSION {
+        return Err(corrupted(IOErrorKind::InvalidData, BtcError::UnsupportedFileVersion));
+    }
+    let len = u64::from_be_bytes(bytes[6..FILE_HEADER_SIZE].try_into().expect("BUG: Impossible"));
+    let rest = &bytes[FILE_HEADER_SIZE..];
+    if (rest.len() as u64) < len.saturating_add(FILE_CHECKSUM_SIZE as u64) {
+        return Err(corrupted(IOErrorKind::UnexpectedEof, BtcError::TruncatedFile));
+    }
+    let (payload, rest) = rest.split_at(len as usize);
+    if rest.len() != FILE_CHECKSUM_SIZE {
+        return Err(corrupted(IOErrorKind::InvalidData, BtcError::UnknownFileFormat));
+    }
+    if rest != checksum(payload) {
+        return Err(corrupted(IOErrorKind::InvalidData, BtcError::FileChecksumMismatch));
+    }
+    return Ok(payload);
+}
+
+/* replace the file at path with bytes so that a crash leaves
+either the old or the new file. The bytes go to a temporary file
+next to it which is synced and then renamed over the old one */
+pub fn write_atomic<P: AsRef<Path>>(path: P, bytes: &[u8]) -> IOResult<()> {
+    let path = path.as_ref();
+    let mut tmp_name = path.file_name().ok_or_else(|| {
+        IOError::new(IOErrorKind::InvalidInput, "Path is not a file")
+    })?.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+
+    //make the rename itself durable
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    return Ok(());
+}
+
 pub trait Saveable 
 where 
     Self: Sized{
     fn load<I: Read>(reader: I) -> IOResult<Self>;
     fn save<O: Write>(self: &Self, writer: O) -> IOResult<()>;
     fn save_to_file<P: AsRef<Path>>(self: &Self, path: P) -> IOResult<()> {
-        let file = File::create(&path)?;
-        return self.save(file);
+        let mut payload = Vec::new();
+        self.save(&mut payload)?;
+        return write_atomic(path, &seal_file(&payload));
     }
     fn load_from_file<P: AsRef<Path>>(path: P) -> IOResult<Self> {
-        let file = File::open(&path)?;
-        return Self::load(file);
+        let bytes = fs::read(&path)?;
+        return Self::load(open_file(&bytes)?);
+    }
+}
+//a directory of its own for one test, removed when dropped
+#[cfg(test)]
+pub(crate) struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("btclib-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        return TestDir(path);
+    }
+
+    pub(crate) fn path(self: &Self) -> &Path {
+        return &self.0;
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+
+    fn drop(self: &mut Self) {
+        let _ = std::fs::remove_dir_all(&self.0);
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,4 @@
-use std::{env, usize};
+use std::env;
 use std::process::exit;
 
 use btclib::types::Block;
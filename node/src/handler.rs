@@ -0,0 +1,201 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::task;
+use tokio::time::{sleep, timeout};
+use tokio_util::codec::Framed;
+
+use btclib::error::BtcError;
+use btclib::network::{self, Frame, Message, MessageCodec, Network, Services, Version};
+use btclib::types::BlockChain;
+
+use crate::peers::{BanList, Misbehavior, Peer};
+
+//drop a peer that sends nothing for this long, or stops reading
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+const USER_AGENT: &str = concat!("/node:", env!("CARGO_PKG_VERSION"), "/");
+
+//what we tell peers about ourselves in the handshake
+pub fn our_version(blockchain: &BlockChain, network: Network) -> Version {
+    let mut services = if blockchain.pruned_height() > 0 {
+        Services::RECENT_BLOCKS
+    } else {
+        Services::FULL_BLOCKS
+    };
+    if blockchain.index().is_some() {
+        services = services | Services::INDEX;
+    }
+    return Version::new(network, blockchain.block_height(), services, USER_AGENT);
+}
+
+/* answer messages from one peer until it disconnects, goes
+quiet, or misbehaves enough to be banned. Runs as a task, the
+chain is worked on with block_in_place so other connections
+keep being served meanwhile. nodes are the addresses handed out
+to peers discovering others */
+pub async fn handle_connection(stream: TcpStream, blockchain: Arc<Mutex<BlockChain>>,
+                               network: Network, bans: Arc<BanList>, nodes: Arc<Vec<String>>) {
+    let Ok(addr) = stream.peer_addr() else {
+        return;
+    };
+    if bans.is_banned(addr.ip()) {
+        return;
+    }
+    let mut framed = Framed::new(stream, MessageCodec);
+    let ours = our_version(&blockchain.lock().expect("BUG: Impossible"), network);
+    match timeout(IDLE_TIMEOUT, network::accept_handshake_async(&mut framed, &ours)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            eprintln!("Refused connection from {}: {}", addr, e);
+            return;
+        }
+        Err(_) => return,
+    }
+
+    let mut peer = Peer::new(addr);
+    loop {
+        let message = match timeout(IDLE_TIMEOUT, framed.next()).await {
+            Ok(Some(Ok(Frame::Message(message, len)))) => {
+                sleep(peer.throttle(len)).await;
+                message
+            }
+            //a message we do not know is skipped, but not for free
+            Ok(Some(Ok(Frame::Unknown(len)))) => {
+                sleep(peer.throttle(len)).await;
+                peer.misbehaved(Misbehavior::UnknownMessage);
+                if peer.should_ban() {
+                    break;
+                }
+                continue;
+            }
+            //disconnected or timed out
+            Ok(Some(Err(ciborium::de::Error::Io(_)))) | Ok(None) | Err(_) => return,
+            Ok(Some(Err(e))) => {
+                eprintln!("Malformed message from {}: {}", addr, e);
+                peer.misbehaved(Misbehavior::MalformedMessage);
+                break;
+            }
+        };
+        let response = task::block_in_place(|| handle_message(message, &blockchain, &nodes, &mut peer));
+        if peer.should_ban() {
+            break;
+        }
+        let Some(response) = response else {
+            continue;
+        };
+        match timeout(IDLE_TIMEOUT, framed.send(response)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                eprintln!("Failed to send response: {}", e);
+                return;
+            }
+            Err(_) => return,
+        }
+    }
+    if peer.should_ban() {
+        eprintln!("Banning {}", addr.ip());
+        bans.ban(addr.ip());
+    }
+}
+
+fn handle_message(message: Message, blockchain: &Mutex<BlockChain>, nodes: &[String],
+                  peer: &mut Peer) -> Option<Message> {
+    let mut blockchain = blockchain.lock().expect("BUG: Impossible");
+    return match message {
+        Message::FetchUTXOs(pubkey) => {
+            let mut utxos = blockchain.utxos_for(&pubkey);
+            //outputs of pending tx can be spent before they confirm
+            utxos.extend(blockchain.mempool().outputs()
+                            .filter(|(output, _)| output.lock.is_owned_by(&pubkey))
+                            .map(|(output, spent)| (output.clone(), spent)));
+            Some(Message::UTXOs(utxos))
+        }
+        Message::SubmitTransaction(transaction) | Message::NewTransaction(transaction) => {
+            //a transaction sent again is not held against the peer
+            if blockchain.mempool().contains(&transaction.hash()) {
+                return None;
+            }
+            match blockchain.add_to_mempool(transaction) {
+                Ok(()) => {}
+                //valid, just not wanted by our mempool right now
+                Err(e @ (BtcError::InsufficientFee | BtcError::InsufficientReplacementFee
+                         | BtcError::TooManyReplacements | BtcError::MempoolChainLimit)) => {
+                    println!("Rejected transaction: {}", e);
+                }
+                Err(e) => {
+                    println!("Rejected transaction: {}", e);
+                    peer.misbehaved(Misbehavior::InvalidTransaction);
+                }
+            }
+            None
+        }
+        Message::FetchTemplate(pubkey) => {
+            Some(Message::Template(blockchain.block_template(&pubkey)))
+        }
+        Message::ValidateTemplate(block) => {
+            let last_hash = blockchain.last_block_hash();
+            Some(Message::TemplateValidity(block.header.prev_block_hash == last_hash))
+        }
+        Message::SubmitTemplate(block) | Message::NewBlock(block) => {
+            //a block not on our tip may be stale or on a fork we
+            //do not follow yet, only one that is fails for itself
+            let on_tip = block.header.prev_block_hash == blockchain.last_block_hash();
+            match blockchain.add_block(block) {
+                Ok(()) => {}
+                Err(BtcError::BlockStoreFailure) => eprintln!("Failed to store block"),
+                Err(e) => {
+                    println!("Rejected block: {}", e);
+                    if on_tip {
+                        peer.misbehaved(Misbehavior::InvalidBlock);
+                    }
+                }
+            }
+            None
+        }
+        Message::FetchBlock(height) => {
+            match blockchain.block(height as u64) {
+                Some(block) => Some(Message::NewBlock(block)),
+                None => Some(Message::BlockNotAvailable(height)),
+            }
+        }
+        Message::FetchHeaders(start, count) => {
+            let count = count.min(btclib::MAX_HEADERS_PER_MESSAGE) as u64;
+            let headers = (start as u64..(start as u64).saturating_add(count))
+                            .map_while(|height| blockchain.header(height))
+                            .collect();
+            Some(Message::Headers(headers))
+        }
+        Message::LocateFork(locator) => {
+            let fork = blockchain.find_fork(&locator);
+            let start = fork.map(|height| height + 1).unwrap_or(0);
+            let end = blockchain.block_height()
+                        .min(start + btclib::MAX_HEADERS_PER_MESSAGE as u64);
+            let hashes = (start..end).filter_map(|height| blockchain.block_hash(height)).collect();
+            Some(Message::ForkPoint(fork.map(|height| height as usize), hashes))
+        }
+        Message::DiscoverNodes => Some(Message::NodeList(nodes.to_vec())),
+        Message::AskDifference(height) => {
+            Some(Message::Difference(blockchain.block_height() as i32 - height as i32))
+        }
+        Message::FetchFeeEstimate(target) => {
+            Some(Message::FeeEstimate(blockchain.estimate_fee_rate(target)))
+        }
+        Message::FetchTransaction(txid) => {
+            if blockchain.index().is_none() {
+                return Some(Message::IndexNotAvailable);
+            }
+            Some(Message::Transaction(blockchain.transaction(&txid)))
+        }
+        Message::FetchHistory(pubkey) => match blockchain.history(&pubkey) {
+            Some(history) => Some(Message::History(history)),
+            None => Some(Message::IndexNotAvailable),
+        },
+        _ => {
+            peer.misbehaved(Misbehavior::UnexpectedMessage);
+            None
+        }
+    };
+}
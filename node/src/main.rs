@@ -1,3 +1,239 @@
+#![allow(clippy::needless_return,
+        clippy::needless_arbitrary_self_type)]
+mod handler;
+mod peers;
+mod sync;
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use btclib::network::Network;
+use btclib::sha256::Hash;
+use btclib::types::{BlockChain, SavedMempool, UtxoSnapshot};
+use btclib::util::Saveable;
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+
+//seconds between saving the mempool
+const SAVE_INTERVAL: u64 = 60;
+//seconds between syncing with peers
+const SYNC_INTERVAL: u64 = 30;
+//mempool is saved next to the blocks
+const MEMPOOL_FILE: &str = "mempool.dat";
+
+fn usage() -> ! {
+    eprintln!("Usage: node <port> <data_dir> [--snapshot <snapshot_file>] \
+               [--snapshot-commitment <hash>] [--prune <blocks>] [--index] [--network <mainnet|testnet>] \
+               [--peer <address>]...");
+    exit(1);
+}
+
+#[derive(Default)]
+struct Options {
+    snapshot: Option<String>,
+    //refuse a snapshot whose commitment is not this
+    snapshot_commitment: Option<Hash>,
+    prune: Option<u64>,
+    index: bool,
+    network: Network,
+    peers: Vec<String>,
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Options {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--snapshot" => options.snapshot = Some(args.next().unwrap_or_else(|| usage())),
+            "--snapshot-commitment" => {
+                let hash = args.next().unwrap_or_else(|| usage());
+                options.snapshot_commitment = Some(Hash::from_hex(&hash).unwrap_or_else(|| {
+                    eprintln!("<hash> should be a hex snapshot commitment");
+                    exit(1);
+                }));
+            }
+            "--index" => options.index = true,
+            "--network" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                options.network = Network::from_name(&name).unwrap_or_else(|| usage());
+            }
+            "--peer" => options.peers.push(args.next().unwrap_or_else(|| usage())),
+            "--prune" => {
+                let value = args.next().unwrap_or_else(|| usage());
+                options.prune = Some(value.parse().unwrap_or_else(|_| {
+                    eprintln!("<blocks> should be a number of blocks");
+                    exit(1);
+                }));
+            }
+            _ => usage(),
+        }
+    }
+    return options;
+}
+
+fn prune(blockchain: &mut BlockChain, keep: u64) {
+    match blockchain.set_prune(keep) {
+        Ok(()) => println!("Pruning, blocks below {} removed", blockchain.pruned_height()),
+        Err(e) => eprintln!("Failed to prune blocks: {}", e),
+    }
+}
+
+/* open the chain in dir, or start a new one, and put back the
+saved mempool. Restoring revalidates every transaction and
+resets the reservation flags to the ones still spent */
+fn load(dir: &Path, snapshot: Option<UtxoSnapshot>) -> BlockChain {
+    let mut blockchain = match snapshot {
+        Some(snapshot) => BlockChain::open_snapshot(dir, snapshot)
+                            .expect("Failed to open blockchain from snapshot"),
+        None => BlockChain::open(dir).expect("Failed to open blockchain"),
+    };
+    println!("Opened blockchain at height {}", blockchain.block_height());
+
+    let mempool_path = dir.join(MEMPOOL_FILE);
+    let saved = if mempool_path.exists() {
+        SavedMempool::load_from_file(&mempool_path).unwrap_or_else(|e| {
+            eprintln!("Failed to load mempool, starting empty: {}", e);
+            SavedMempool::default()
+        })
+    } else {
+        SavedMempool::default()
+    };
+    let restored = blockchain.restore_mempool(saved);
+    println!("Restored {} mempool transactions", restored);
+    return blockchain;
+}
+
+//blocks and the utxo changes they make are written as they
+//arrive, only the mempool needs saving
+fn save(blockchain: &BlockChain, dir: &Path) {
+    if let Err(e) = blockchain.saved_mempool().save_to_file(dir.join(MEMPOOL_FILE)) {
+        eprintln!("Failed to save mempool: {}", e);
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    let (port, dir) = if let (Some(arg1), Some(arg2)) = (env::args().nth(1), env::args().nth(2)) {
+        (arg1, PathBuf::from(arg2))
+    } else {
+        usage();
+    };
+    let port: u16 = port.parse().unwrap_or_else(|_| {
+        eprintln!("<port> should be a port number");
+        exit(1);
+    });
+
+    let options = parse_options(env::args().skip(3));
+    let snapshot = options.snapshot.map(|path| {
+        let snapshot = UtxoSnapshot::load_from_file(path).expect("Failed to load snapshot");
+        println!("Snapshot at height {} with commitment {}",
+                 snapshot.height, snapshot.commitment());
+        if let Some(expected) = options.snapshot_commitment
+            && snapshot.commitment() != expected {
+            eprintln!("Snapshot commitment is not the expected {}", expected);
+            exit(1);
+        }
+        snapshot
+    });
+
+    let mut blockchain = load(&dir, snapshot.clone());
+    if options.index {
+        //building the index needs every block, so it comes
+        //before pruning
+        blockchain.enable_index().expect("Failed to build indexes");
+        println!("Keeping transaction and address indexes");
+    }
+    let blockchain = Arc::new(Mutex::new(blockchain));
+
+    /* check the snapshot against the blocks it skipped, a node
+    running on a bad utxo set must not keep going. Those blocks
+    are only pruned once it checks out. A node that never had them
+    fetches them from its peers once it has the headers */
+    if let Some(snapshot) = snapshot {
+        let blockchain = blockchain.clone();
+        let peers = options.peers.clone();
+        thread::spawn(move || {
+            while blockchain.lock().expect("BUG: Impossible").pending_snapshot().is_some() {
+                thread::sleep(Duration::from_secs(SYNC_INTERVAL));
+            }
+            let copy = blockchain.lock().expect("BUG: Impossible").clone();
+            let verified = if copy.pruned_height() == 0 {
+                copy.verify_snapshot(&snapshot, |height| copy.block(height))
+            } else {
+                let ours = handler::our_version(&copy, options.network);
+                copy.verify_snapshot(&snapshot, sync::block_fetcher(peers, ours))
+            };
+            match verified {
+                Ok(()) => {
+                    println!("Snapshot verified");
+                    if let Some(keep) = options.prune {
+                        prune(&mut blockchain.lock().expect("BUG: Impossible"), keep);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Snapshot failed verification: {}", e);
+                    exit(1);
+                }
+            }
+        });
+    } else if let Some(keep) = options.prune {
+        prune(&mut blockchain.lock().expect("BUG: Impossible"), keep);
+    }
+
+    //save everything on ctrl-c
+    {
+        let blockchain = blockchain.clone();
+        let dir = dir.clone();
+        ctrlc::set_handler(move || {
+            let blockchain = blockchain.lock().expect("BUG: Impossible");
+            save(&blockchain, &dir);
+            println!("Saved, shutting down");
+            exit(0);
+        }).expect("Failed to set shutdown handler");
+    }
+
+    //expire old mempool tx and save periodically
+    {
+        let blockchain = blockchain.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(SAVE_INTERVAL));
+            let mut blockchain = blockchain.lock().expect("BUG: Impossible");
+            blockchain.cleanup_mempool();
+            save(&blockchain, &dir);
+        });
+    }
+
+    //peers we were given are the ones we tell others about
+    let nodes = Arc::new(options.peers.clone());
+
+    //catch up with peers and keep doing so
+    if !options.peers.is_empty() {
+        let blockchain = blockchain.clone();
+        thread::spawn(move || loop {
+            sync::sync(&options.peers, &blockchain, options.network);
+            thread::sleep(Duration::from_secs(SYNC_INTERVAL));
+        });
+    }
+
+    let runtime = Runtime::new().expect("Failed to start runtime");
+    runtime.block_on(listen(port, blockchain, options.network, nodes));
+}
+
+//serve every incoming connection as a task on the runtime
+async fn listen(port: u16, blockchain: Arc<Mutex<BlockChain>>, network: Network,
+                nodes: Arc<Vec<String>>) {
+    let bans = Arc::new(peers::BanList::default());
+    let listener = TcpListener::bind(("0.0.0.0", port)).await.expect("Failed to bind port");
+    println!("Listening on port {}", port);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let (blockchain, bans, nodes) = (blockchain.clone(), bans.clone(), nodes.clone());
+                tokio::spawn(handler::handle_connection(stream, blockchain, network, bans, nodes));
+            }
+            Err(e) => eprintln!("Failed to accept connection: {}", e),
+        }
+    }
 }
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+//misbehavior score at which a peer is disconnected and banned
+const BAN_SCORE: u32 = 100;
+//how long a banned address is refused
+const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+//messages a peer may send per second on average, and at once
+//after being quiet
+const RATE_LIMIT: f64 = 200.0;
+const RATE_BURST: f64 = 1000.0;
+//bytes a peer may send per second on average, and at once after
+//being quiet, so a few large messages are slowed down as well
+const BYTE_RATE_LIMIT: f64 = 4.0 * 1024.0 * 1024.0;
+const BYTE_RATE_BURST: f64 = 32.0 * 1024.0 * 1024.0;
+
+//things a peer can do wrong, each adding to its score
+#[derive(Debug, Clone, Copy)]
+pub enum Misbehavior {
+    //a frame too large for its size class, or a body that is not
+    //a message of the type and class in its frame header
+    MalformedMessage,
+    //a frame of a type we do not know, which a peer may send for
+    //a message newer than ours
+    UnknownMessage,
+    //a response we never asked for, or a second handshake
+    UnexpectedMessage,
+    //a block on top of our tip that did not validate
+    InvalidBlock,
+    //a transaction that did not validate, as opposed to one
+    //turned away by mempool policy
+    InvalidTransaction,
+}
+
+impl Misbehavior {
+
+    fn score(self: Self) -> u32 {
+        return match self {
+            Misbehavior::MalformedMessage | Misbehavior::InvalidBlock => BAN_SCORE,
+            Misbehavior::UnexpectedMessage => 20,
+            Misbehavior::InvalidTransaction => 10,
+            Misbehavior::UnknownMessage => 1,
+        };
+    }
+}
+
+//addresses refused until their ban runs out, shared by all
+//connections
+#[derive(Default)]
+pub struct BanList {
+    banned: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl BanList {
+
+    pub fn is_banned(self: &Self, addr: IpAddr) -> bool {
+        let mut banned = self.banned.lock().expect("BUG: Impossible");
+        let now = Instant::now();
+        banned.retain(|_, until| *until > now);
+        return banned.contains_key(&addr);
+    }
+
+    pub fn ban(self: &Self, addr: IpAddr) {
+        let until = Instant::now() + BAN_DURATION;
+        self.banned.lock().expect("BUG: Impossible").insert(addr, until);
+    }
+}
+
+/* one connected peer, how badly it behaved and how many messages
+and bytes it may still send right away. Messages past either rate
+limit are not refused, the peer is slowed down to it */
+pub struct Peer {
+    pub addr: SocketAddr,
+    score: u32,
+    tokens: f64,
+    byte_tokens: f64,
+    last: Instant,
+}
+
+impl Peer {
+
+    pub fn new(addr: SocketAddr) -> Self {
+        return Peer {
+            addr,
+            score: 0,
+            tokens: RATE_BURST,
+            byte_tokens: BYTE_RATE_BURST,
+            last: Instant::now(),
+        };
+    }
+
+    //take a message of len bytes from the peer's allowance,
+    //returns how long to wait before handling it
+    pub fn throttle(self: &mut Self, len: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * RATE_LIMIT).min(RATE_BURST) - 1.0;
+        self.byte_tokens = (self.byte_tokens + elapsed * BYTE_RATE_LIMIT).min(BYTE_RATE_BURST)
+                            - len as f64;
+        self.last = now;
+        let wait = (-self.tokens / RATE_LIMIT).max(-self.byte_tokens / BYTE_RATE_LIMIT);
+        if wait <= 0.0 {
+            return Duration::ZERO;
+        }
+        return Duration::from_secs_f64(wait);
+    }
+
+    pub fn misbehaved(self: &mut Self, misbehavior: Misbehavior) {
+        self.score = self.score.saturating_add(misbehavior.score());
+        eprintln!("Peer {} misbehaved: {:?}, score {}", self.addr, misbehavior, self.score);
+    }
+
+    pub fn should_ban(self: &Self) -> bool {
+        return self.score >= BAN_SCORE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_messages_are_throttled_by_bytes() {
+        let mut peer = Peer::new("127.0.0.1:9000".parse().unwrap());
+        assert_eq!(peer.throttle(BYTE_RATE_BURST as u64), Duration::ZERO);
+        //a second of bytes past the burst waits about a second,
+        //though it is far from the message limit
+        let wait = peer.throttle(BYTE_RATE_LIMIT as u64);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        assert_eq!(Peer::new(peer.addr).throttle(1), Duration::ZERO);
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use btclib::U256;
+use btclib::error::BtcError;
+use btclib::network::{self, Message, Network, Version};
+use btclib::sha256::Hash;
+use btclib::types::{Block, BlockChain, BlockHeader};
+
+use crate::handler::our_version;
+
+//give up on a peer that takes longer than this to answer
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+//headers taken from one peer in a sync, the rest wait for the
+//next one
+const MAX_HEADERS_PER_PEER: usize = 50 * btclib::MAX_HEADERS_PER_MESSAGE as usize;
+//fork blocks held in memory to switch over to a chain with more
+//work, a fork that needs more to overtake ours is not followed
+const MAX_REORG_BLOCKS: usize = 500;
+
+//headers a peer has after the point its chain parts from ours
+struct PeerHeaders {
+    peer: String,
+    start: u64,
+    headers: Vec<BlockHeader>,
+}
+
+impl PeerHeaders {
+
+    //the headers it has from height on
+    fn from_height(self: &Self, height: u64) -> PeerHeaders {
+        let skip = height.saturating_sub(self.start) as usize;
+        let headers = self.headers.get(skip..).unwrap_or_default().to_vec();
+        return PeerHeaders { peer: self.peer.clone(), start: height.max(self.start), headers };
+    }
+}
+
+//connect and handshake, peers on another network are dropped
+fn connect(peer: &str, ours: &Version) -> Option<TcpStream> {
+    let mut stream = TcpStream::connect(peer).map_err(|e| {
+        eprintln!("Failed to connect to {}: {}", peer, e);
+    }).ok()?;
+    stream.set_read_timeout(Some(PEER_TIMEOUT)).ok()?;
+    stream.set_write_timeout(Some(PEER_TIMEOUT)).ok()?;
+    network::connect_handshake(&mut stream, ours).map_err(|e| {
+        eprintln!("Handshake with {} failed: {}", peer, e);
+    }).ok()?;
+    return Some(stream);
+}
+
+fn request(stream: &mut TcpStream, message: Message) -> Option<Message> {
+    message.send(stream).ok()?;
+    return Message::recieve(stream).ok();
+}
+
+/* blocks by height from whichever of peers has them, moving on to
+the next peer when one fails. For checking a snapshot against the
+blocks below it, which a node opened from it never downloads */
+pub fn block_fetcher(peers: Vec<String>, ours: Version) -> impl FnMut(u64) -> Option<Block> {
+    let mut stream: Option<TcpStream> = None;
+    let mut next = 0;
+    return move |height| {
+        for _ in 0..peers.len() {
+            if stream.is_none() {
+                stream = connect(&peers[next % peers.len()], &ours);
+                next += 1;
+            }
+            let Some(connection) = &mut stream else {
+                continue;
+            };
+            if let Some(Message::NewBlock(block)) =
+                request(connection, Message::FetchBlock(height as usize)) {
+                return Some(block);
+            }
+            stream = None;
+        }
+        return None;
+    };
+}
+
+fn chain_work(headers: &[BlockHeader]) -> U256 {
+    return headers.iter().fold(U256::zero(), |work, header| {
+        work.saturating_add(header.work())
+    });
+}
+
+//how many of the first headers of a fork add more work than ours
+//after the fork point, None if that is more than MAX_REORG_BLOCKS
+fn blocks_to_switch(headers: &[BlockHeader], ours: U256) -> Option<usize> {
+    let mut work = U256::zero();
+    for (idx, header) in headers.iter().take(MAX_REORG_BLOCKS).enumerate() {
+        work = work.saturating_add(header.work());
+        if work > ours {
+            return Some(idx + 1);
+        }
+    }
+    return None;
+}
+
+/* find where a peer's chain parts from ours with our locator,
+then fetch the headers it has after that, up to
+MAX_HEADERS_PER_PEER, checked to follow on from our last shared
+block as each batch comes in. Whatever checked out is kept if the
+peer stops answering or sends a bad batch. None if the peer has
+nothing we lack */
+fn fetch_headers(peer: &str, locator: &[Hash], blockchain: &Mutex<BlockChain>,
+                 ours: &Version) -> Option<PeerHeaders> {
+    let mut stream = connect(peer, ours)?;
+    let Some(Message::ForkPoint(fork, hashes)) =
+        request(&mut stream, Message::LocateFork(locator.to_vec())) else {
+        eprintln!("No fork point from {}", peer);
+        return None;
+    };
+    if hashes.is_empty() {
+        return None;
+    }
+    let start = fork.map(|fork| fork as u64 + 1).unwrap_or(0);
+    //the headers before each batch, for checking its targets
+    let mut earlier = blockchain.lock().expect("BUG: Impossible").last_headers(start);
+    if earlier.len() as u64 != start.min(btclib::DIFFICULTY_UPDATE_INETRVAL) {
+        return None;
+    }
+
+    let mut headers: Vec<BlockHeader> = Vec::new();
+    while headers.len() < MAX_HEADERS_PER_PEER {
+        let height = start + headers.len() as u64;
+        let message = Message::FetchHeaders(height as usize, btclib::MAX_HEADERS_PER_MESSAGE);
+        let Some(Message::Headers(batch)) = request(&mut stream, message) else {
+            eprintln!("No headers from {}", peer);
+            break;
+        };
+        if let Err(e) = BlockHeader::check_chain(height, &earlier, &batch) {
+            eprintln!("Invalid headers from {}: {}", peer, e);
+            break;
+        }
+        let done = batch.len() < btclib::MAX_HEADERS_PER_MESSAGE as usize;
+        earlier.extend(batch.iter().cloned());
+        let keep = earlier.len().saturating_sub(btclib::DIFFICULTY_UPDATE_INETRVAL as usize);
+        earlier.drain(..keep);
+        headers.extend(batch);
+        if done {
+            break;
+        }
+    }
+    return Some(PeerHeaders { peer: peer.to_owned(), start, headers });
+}
+
+/* take heights off the queue and fetch their blocks from peer,
+which has the blocks of hashes, from height base on. A height
+the peer fails to deliver goes back on the queue for the others */
+fn fetch_bodies(peer: &str, base: u64, hashes: &[Hash], queue: &Mutex<VecDeque<u64>>,
+                stop: &AtomicBool, sender: Sender<(u64, Block)>, ours: &Version) {
+    let Some(mut stream) = connect(peer, ours) else {
+        return;
+    };
+    while !stop.load(Ordering::Relaxed) {
+        let Some(height) = queue.lock().expect("BUG: Impossible").pop_front() else {
+            return;
+        };
+        if height >= base + hashes.len() as u64 {
+            queue.lock().expect("BUG: Impossible").push_front(height);
+            return;
+        }
+        let expected = hashes[(height - base) as usize];
+        let block = match request(&mut stream, Message::FetchBlock(height as usize)) {
+            Some(Message::NewBlock(block)) if block.hash() == expected => block,
+            _ => {
+                eprintln!("Failed to fetch block {} from {}", height, peer);
+                queue.lock().expect("BUG: Impossible").push_front(height);
+                return;
+            }
+        };
+        if sender.send((height, block)).is_err() {
+            return;
+        }
+    }
+}
+
+/* fetch the bodies of best from every peer that has them at once
+and hand them to consume in order, until it returns false.
+Returns how many were consumed */
+fn download(candidates: &[PeerHeaders], best: &PeerHeaders, ours: &Version,
+            mut consume: impl FnMut(Block) -> bool) -> u64 {
+    let height = best.start;
+    let hashes: Vec<Hash> = best.headers.iter().map(|header| header.hash()).collect();
+    let queue: Mutex<VecDeque<u64>> = Mutex::new((height..height + hashes.len() as u64).collect());
+    let stop = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+    let mut next = height;
+
+    thread::scope(|scope| {
+        for candidate in candidates.iter().filter(|candidate| candidate.start == height) {
+            let shared = candidate.headers.iter().zip(&hashes)
+                            .take_while(|(header, hash)| header.hash() == **hash)
+                            .count();
+            if shared == 0 {
+                continue;
+            }
+            let sender = sender.clone();
+            let (hashes, queue, stop) = (&hashes, &queue, &stop);
+            scope.spawn(move || {
+                fetch_bodies(&candidate.peer, height, &hashes[..shared],
+                             queue, stop, sender, ours);
+            });
+        }
+        drop(sender);
+
+        //blocks arrive in any order, hold on to them until the
+        //ones before are in
+        let mut pending = BTreeMap::new();
+        for (height, block) in receiver {
+            if stop.load(Ordering::Relaxed) {
+                continue;
+            }
+            pending.insert(height, block);
+            while let Some(block) = pending.remove(&next) {
+                if !consume(block) {
+                    stop.store(true, Ordering::Relaxed);
+                    break;
+                }
+                next += 1;
+            }
+        }
+    });
+    return next - height;
+}
+
+//work our chain has after the first start blocks
+fn work_after(blockchain: &BlockChain, start: u64) -> U256 {
+    let headers: Vec<BlockHeader> = (start..blockchain.block_height())
+                                    .filter_map(|height| blockchain.header(height))
+                                    .collect();
+    return chain_work(&headers);
+}
+
+/* headers-first sync: find where each peer's chain parts from
+ours and collect the headers it has after that, pick the chain
+adding the most work and download its blocks from all the peers
+that have them. Blocks on top of our tip are added as they come,
+a fork with more work replaces our blocks after the fork point
+with as few of its blocks as it takes to overtake them, the rest
+are added as they come */
+pub fn sync(peers: &[String], blockchain: &Mutex<BlockChain>, network: Network) {
+    let (mut height, locator, ours, pending) = {
+        let blockchain = blockchain.lock().expect("BUG: Impossible");
+        (blockchain.block_height(), blockchain.locator(), our_version(&blockchain, network),
+         blockchain.pending_snapshot())
+    };
+
+    let mut candidates: Vec<PeerHeaders> = thread::scope(|scope| {
+        let handles: Vec<_> = peers.iter().map(|peer| {
+            let (locator, ours) = (&locator, &ours);
+            scope.spawn(move || fetch_headers(peer, locator, blockchain, ours))
+        }).collect();
+        return handles.into_iter().filter_map(|handle| handle.join().ok().flatten()).collect();
+    });
+
+    //work each chain adds over what ours has after the fork
+    let best = {
+        let blockchain = blockchain.lock().expect("BUG: Impossible");
+        candidates.iter().enumerate().filter_map(|(idx, candidate)| {
+            let gain = chain_work(&candidate.headers)
+                        .checked_sub(work_after(&blockchain, candidate.start))
+                        .filter(|gain| !gain.is_zero())?;
+            Some((gain, idx))
+        }).max_by_key(|(gain, _)| *gain).map(|(_, idx)| idx)
+    };
+    let Some(best) = best else {
+        return;
+    };
+
+    /* a chain opened from a snapshot takes the headers below it
+    without their blocks, the blocks are fetched from there on */
+    if let Some(snapshot) = pending {
+        let chosen = &candidates[best];
+        if chosen.start != height || chosen.start + (chosen.headers.len() as u64) < snapshot {
+            println!("No peer has the headers up to the snapshot yet");
+            return;
+        }
+        let below = &chosen.headers[..(snapshot - height) as usize];
+        if let Err(e) = blockchain.lock().expect("BUG: Impossible").add_headers(below) {
+            eprintln!("Rejected headers below the snapshot: {}", e);
+            return;
+        }
+        println!("Added {} headers up to the snapshot from {}", below.len(), chosen.peer);
+        candidates = candidates.iter().map(|candidate| candidate.from_height(snapshot)).collect();
+        height = snapshot;
+    }
+    let best = &candidates[best];
+
+    if best.start >= height {
+        println!("Syncing {} blocks, best chain from {}", best.headers.len(), best.peer);
+        let added = download(&candidates, best, &ours, |block| {
+            let mut blockchain = blockchain.lock().expect("BUG: Impossible");
+            if let Err(e) = blockchain.add_block(block) {
+                eprintln!("Rejected block: {}", e);
+                return false;
+            }
+            return true;
+        });
+        println!("Synced {} blocks", added);
+        return;
+    }
+
+    let replaced = work_after(&blockchain.lock().expect("BUG: Impossible"), best.start);
+    let Some(switch) = blocks_to_switch(&best.headers, replaced) else {
+        println!("Fork at height {} from {} is too deep to follow", best.start, best.peer);
+        return;
+    };
+    println!("Fork at height {} from {}, fetching {} blocks, switching after {}",
+             best.start, best.peer, best.headers.len(), switch);
+    let mut blocks = Vec::new();
+    let mut switched = false;
+    let added = download(&candidates, best, &ours, |block| {
+        if !switched {
+            blocks.push(block);
+            if blocks.len() < switch {
+                return true;
+            }
+            let mut blockchain = blockchain.lock().expect("BUG: Impossible");
+            return match blockchain.reorganize(best.start, std::mem::take(&mut blocks)) {
+                Ok(()) => {
+                    println!("Reorganized onto {} blocks from {}", switch, best.peer);
+                    switched = true;
+                    true
+                }
+                //the chain is neither the old one nor the new one, only
+                //reopening it from disk gets back to a known state
+                Err(BtcError::ChainInconsistent) => {
+                    eprintln!("Failed to reorganize: {}, exiting", BtcError::ChainInconsistent);
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Failed to reorganize: {}", e);
+                    false
+                }
+            };
+        }
+        let mut blockchain = blockchain.lock().expect("BUG: Impossible");
+        if let Err(e) = blockchain.add_block(block) {
+            eprintln!("Rejected block: {}", e);
+            return false;
+        }
+        return true;
+    });
+    println!("Synced {} blocks", added);
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use btclib::types::Transactions;
+    use btclib::util::MerkleRoot;
+
+    //headers of equal work, they are not checked here
+    fn headers(count: usize) -> Vec<BlockHeader> {
+        let merkle_root = MerkleRoot::calculate(&[Transactions::new(vec![], vec![])]);
+        return (0..count).map(|_| {
+            BlockHeader::new(Utc::now(), 0, Hash::zero(), merkle_root, btclib::MIN_TARGET)
+        }).collect();
+    }
+
+    #[test]
+    fn switch_after_the_first_block_with_more_work() {
+        let fork = headers(10);
+        let ours = chain_work(&fork[..3]);
+        assert_eq!(blocks_to_switch(&fork, ours), Some(4));
+        assert_eq!(blocks_to_switch(&fork, U256::zero()), Some(1));
+        assert_eq!(blocks_to_switch(&fork, chain_work(&fork)), None);
+    }
+
+    #[test]
+    fn fork_needing_too_many_blocks_is_not_followed() {
+        let fork = headers(MAX_REORG_BLOCKS + 10);
+        let ours = chain_work(&fork[..MAX_REORG_BLOCKS - 1]);
+        assert_eq!(blocks_to_switch(&fork, ours), Some(MAX_REORG_BLOCKS));
+        let ours = chain_work(&fork[..MAX_REORG_BLOCKS]);
+        assert_eq!(blocks_to_switch(&fork, ours), None);
+    }
+}
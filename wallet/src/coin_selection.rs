@@ -0,0 +1,309 @@
+use rand::seq::SliceRandom;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use btclib::crypto::{PrivateKey, PubKeyHash, Signature};
+use btclib::error::{BtcError, Result};
+use btclib::sha256::Hash;
+use btclib::types::{OutputLock, Transactions, TransactionsInput, TransactionsOutput};
+
+use crate::wallet::OwnedOutput;
+
+//give up on branch-and-bound after this many tries
+const BNB_MAX_TRIES: usize = 100_000;
+
+//how to pick the outputs funding a payment
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    //look for inputs matching the payment exactly so no change
+    //output is needed, falls back to largest first
+    #[default]
+    BranchAndBound,
+    //fewest inputs, spends the biggest outputs first
+    LargestFirst,
+    //random order, so the inputs say less about the wallet
+    Random,
+}
+
+impl FromStr for Strategy {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        return match s {
+            "bnb" => Ok(Strategy::BranchAndBound),
+            "largest" => Ok(Strategy::LargestFirst),
+            "random" => Ok(Strategy::Random),
+            _ => Err(BtcError::InvalidCoinSelection),
+        };
+    }
+}
+
+/* serialized sizes of the parts of a transaction, in bytes.
+Measured on a dummy signed transaction so they follow the
+encoding used on the wire */
+#[derive(Clone, Copy, Debug)]
+pub struct SizeEstimates {
+    pub base: u64,
+    pub input: u64,
+    pub output: u64,
+}
+
+impl SizeEstimates {
+
+    pub fn measure() -> Self {
+        let key = PrivateKey::new_key();
+        let hash = Hash::hash(&0);
+        let input = TransactionsInput {
+            prev_transaction_output_hash: hash,
+            signature: Signature::sign_output(&hash, &key),
+            pubkey: Some(key.public_key()),
+        };
+        let output = TransactionsOutput {
+            value: u64::MAX,
+            unique_id: Uuid::new_v4(),
+            lock: OutputLock::PubKeyHash(PubKeyHash([0xff; 20])),
+        };
+        return SizeEstimates {
+            base: Transactions::new(vec![], vec![]).size() as u64,
+            input: input.size() as u64,
+            output: output.size() as u64,
+        };
+    }
+
+    pub fn transaction(self: &Self, inputs: usize, outputs: usize) -> u64 {
+        return self.base + self.input * inputs as u64 + self.output * outputs as u64;
+    }
+}
+
+//outputs to spend and the value left for change, the
+//rest of the inputs goes to the fee
+#[derive(Clone, Debug)]
+pub struct Selection {
+    pub inputs: Vec<OwnedOutput>,
+    pub change: u64,
+}
+
+/* fee and change bookkeeping for one payment. Every input is
+valued at what it adds after paying for its own bytes, so inputs
+worth less than that are never picked. Amount and fee rate come
+from the user, so the fees are worked out with checked arithmetic
+up front and a payment they overflow is refused */
+struct Target {
+    amount: u64,
+    fee_rate: u64,
+    sizes: SizeEstimates,
+    //fee for the bytes of one input, and of one output
+    input_fee: u64,
+    output_fee: u64,
+    //effective value needed for a payment without change
+    without_change: u64,
+    //adding a change output and spending it later costs this
+    cost_of_change: u64,
+}
+
+impl Target {
+
+    fn new(amount: u64, fee_rate: u64, sizes: SizeEstimates) -> Result<Self> {
+        let fee = |size: u64| fee_rate.checked_mul(size).ok_or(BtcError::InvalidAmount);
+        let input_fee = fee(sizes.input)?;
+        let output_fee = fee(sizes.output)?;
+        let without_change = amount.checked_add(fee(sizes.transaction(0, 1))?)
+                                .ok_or(BtcError::InvalidAmount)?;
+        let cost_of_change = output_fee.checked_add(input_fee).ok_or(BtcError::InvalidAmount)?;
+        without_change.checked_add(cost_of_change).ok_or(BtcError::InvalidAmount)?;
+        return Ok(Target {
+            amount,
+            fee_rate,
+            sizes,
+            input_fee,
+            output_fee,
+            without_change,
+            cost_of_change,
+        });
+    }
+
+    fn effective_value(self: &Self, owned: &OwnedOutput) -> Option<u64> {
+        return owned.output.value.checked_sub(self.input_fee)
+                    .filter(|value| *value > 0);
+    }
+
+    //settle fee and change once the inputs cover the payment,
+    //change worth less than it costs is left to the miner
+    fn finish(self: &Self, inputs: Vec<OwnedOutput>) -> Result<Selection> {
+        let total = inputs.iter().try_fold(0u64, |total, owned| {
+            total.checked_add(owned.output.value)
+        }).ok_or(BtcError::InvalidAmount)?;
+        let fee = self.fee_rate.checked_mul(self.sizes.transaction(inputs.len(), 1))
+                    .ok_or(BtcError::InvalidAmount)?;
+        let needed = self.amount.checked_add(fee).ok_or(BtcError::InvalidAmount)?;
+        let excess = total.checked_sub(needed).ok_or(BtcError::InsufficientFunds)?;
+        if excess > self.cost_of_change {
+            return Ok(Selection { inputs, change: excess - self.output_fee });
+        }
+        return Ok(Selection { inputs, change: 0 });
+    }
+
+    //take inputs in order until they cover the payment
+    fn accumulate(self: &Self, candidates: Vec<&OwnedOutput>) -> Result<Selection> {
+        let mut inputs = Vec::new();
+        let mut effective = 0;
+        for owned in candidates {
+            let Some(value) = self.effective_value(owned) else {
+                continue;
+            };
+            effective = value.saturating_add(effective);
+            inputs.push(owned.clone());
+            if effective >= self.without_change {
+                return self.finish(inputs);
+            }
+        }
+        return Err(BtcError::InsufficientFunds);
+    }
+
+    fn branch_and_bound(self: &Self, candidates: &[&OwnedOutput]) -> Option<Selection> {
+        let mut candidates: Vec<(u64, &OwnedOutput)> = candidates.iter()
+            .filter_map(|owned| Some((self.effective_value(owned)?, *owned)))
+            .collect();
+        candidates.sort_by_key(|(value, _)| std::cmp::Reverse(*value));
+        let values: Vec<u64> = candidates.iter().map(|(value, _)| *value).collect();
+
+        let mut search = BnbSearch {
+            values: &values,
+            target: self.without_change,
+            upper: self.without_change + self.cost_of_change,
+            tries: 0,
+            current: Vec::new(),
+            best: None,
+        };
+        search.search(0, 0, values.iter().fold(0, |sum, value| value.saturating_add(sum)));
+        let (_, best) = search.best?;
+        let inputs = best.into_iter().map(|idx| candidates[idx].1.clone()).collect();
+        return self.finish(inputs).ok();
+    }
+}
+
+/* depth first search over including or skipping each input,
+largest first, for the set whose effective value lands between
+the target and the target plus the cost of change. The set
+wasting the least over the target wins */
+struct BnbSearch<'a> {
+    values: &'a [u64],
+    target: u64,
+    upper: u64,
+    tries: usize,
+    current: Vec<usize>,
+    best: Option<(u64, Vec<usize>)>,
+}
+
+impl BnbSearch<'_> {
+
+    fn search(self: &mut Self, depth: usize, sum: u64, remaining: u64) {
+        if self.tries >= BNB_MAX_TRIES || sum > self.upper {
+            return;
+        }
+        self.tries += 1;
+        if sum >= self.target {
+            let waste = sum - self.target;
+            if self.best.as_ref().is_none_or(|(best, _)| waste < *best) {
+                self.best = Some((waste, self.current.clone()));
+            }
+            return;
+        }
+        if depth == self.values.len() || sum.saturating_add(remaining) < self.target {
+            return;
+        }
+        let value = self.values[depth];
+        self.current.push(depth);
+        self.search(depth + 1, sum.saturating_add(value), remaining.saturating_sub(value));
+        self.current.pop();
+        self.search(depth + 1, sum, remaining.saturating_sub(value));
+    }
+}
+
+/* choose unreserved outputs paying amount plus a fee of fee_rate
+per byte of the finished transaction */
+pub fn select(owned: &[OwnedOutput], amount: u64, fee_rate: u64,
+              strategy: Strategy) -> Result<Selection> {
+    let target = Target::new(amount, fee_rate, SizeEstimates::measure())?;
+    let mut candidates: Vec<&OwnedOutput> = owned.iter()
+                                            .filter(|owned| !owned.reserved)
+                                            .collect();
+    candidates.sort_by_key(|owned| std::cmp::Reverse(owned.output.value));
+    return match strategy {
+        Strategy::BranchAndBound => match target.branch_and_bound(&candidates) {
+            Some(selection) => Ok(selection),
+            None => target.accumulate(candidates),
+        },
+        Strategy::LargestFirst => target.accumulate(candidates),
+        Strategy::Random => {
+            candidates.shuffle(&mut rand::thread_rng());
+            target.accumulate(candidates)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned(value: u64, reserved: bool) -> OwnedOutput {
+        let output = TransactionsOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            lock: OutputLock::PubKeyHash(PubKeyHash([0; 20])),
+        };
+        return OwnedOutput { output, reserved };
+    }
+
+    fn values(selection: &Selection) -> Vec<u64> {
+        let mut values: Vec<u64> = selection.inputs.iter().map(|owned| owned.output.value).collect();
+        values.sort();
+        return values;
+    }
+
+    #[test]
+    fn bnb_finds_exact_match_without_change() {
+        let owned = [owned(100, false), owned(60, false), owned(40, false), owned(30, false)];
+        let selection = select(&owned, 70, 0, Strategy::BranchAndBound).unwrap();
+        assert_eq!(values(&selection), vec![30, 40]);
+        assert_eq!(selection.change, 0);
+
+        //largest first takes the biggest output and makes change
+        let selection = select(&owned, 70, 0, Strategy::LargestFirst).unwrap();
+        assert_eq!(values(&selection), vec![100]);
+        assert_eq!(selection.change, 30);
+    }
+
+    #[test]
+    fn bnb_pays_for_input_bytes() {
+        let sizes = SizeEstimates::measure();
+        let fee_rate = 2;
+        let owned = [owned(50_000, false), owned(20_000, false), owned(9_000, false)];
+        let amount = 29_000 - fee_rate * sizes.transaction(2, 1);
+        let selection = select(&owned, amount, fee_rate, Strategy::BranchAndBound).unwrap();
+        assert_eq!(values(&selection), vec![9_000, 20_000]);
+        assert_eq!(selection.change, 0);
+    }
+
+    #[test]
+    fn bnb_falls_back_to_largest_first() {
+        let owned = [owned(100, false), owned(50, false)];
+        let selection = select(&owned, 70, 0, Strategy::BranchAndBound).unwrap();
+        assert_eq!(values(&selection), vec![100]);
+        assert_eq!(selection.change, 30);
+    }
+
+    #[test]
+    fn reserved_outputs_are_not_spent() {
+        let owned = [owned(100, true), owned(50, false)];
+        let result = select(&owned, 70, 0, Strategy::BranchAndBound);
+        assert!(matches!(result, Err(BtcError::InsufficientFunds)));
+    }
+
+    #[test]
+    fn overflowing_fee_is_refused() {
+        let owned = [owned(100, false)];
+        let result = select(&owned, 70, u64::MAX, Strategy::BranchAndBound);
+        assert!(matches!(result, Err(BtcError::InvalidAmount)));
+    }
+}
@@ -1,3 +1,408 @@
+#![allow(clippy::needless_return,
+        clippy::needless_arbitrary_self_type)]
+mod coin_selection;
+mod wallet;
+
+use std::env;
+use std::io::stdin;
+use std::net::TcpStream;
+use std::process::exit;
+
+use btclib::crypto::{Address, PrivateKey, PublicKey};
+use btclib::error::BtcError;
+use btclib::hd::{generate_mnemonic, parse_mnemonic, ExtendedPublicKey};
+use btclib::keystore::Keystore;
+use btclib::network::{self, Network, Services, Version};
+use btclib::types::PartiallySignedTransaction;
+use btclib::util::Saveable;
+
+use coin_selection::Strategy;
+use wallet::{OwnedOutput, Wallet};
+
+fn usage() -> ! {
+    eprintln!("Usage: wallet new <key_file>");
+    eprintln!("       wallet address <key_file>");
+    eprintln!("       wallet keystore new <keystore_file>");
+    eprintln!("       wallet keystore add <keystore_file> <label>");
+    eprintln!("       wallet keystore list <keystore_file>");
+    eprintln!("       wallet keystore export <keystore_file> <label>");
+    eprintln!("       wallet keystore passwd <keystore_file>");
+    eprintln!("       wallet hd create <wallet_file>");
+    eprintln!("       wallet hd restore <wallet_file> <node>");
+    eprintln!("       wallet hd receive <wallet_file>");
+    eprintln!("       wallet hd balance <wallet_file> <node>");
+    eprintln!("       wallet hd send <wallet_file> <node> <address> <amount> <fee_rate|auto> [strategy]");
+    eprintln!("       wallet hd history <wallet_file>");
+    eprintln!("       wallet hd xpub <wallet_file>");
+    eprintln!("       wallet watch create <wallet_file> [xpub]");
+    eprintln!("       wallet watch import <wallet_file> <public_key_hex>");
+    eprintln!("       wallet psbt create <wallet_file> <node> <address> <amount> <fee_rate|auto> <psbt_file> [strategy]");
+    eprintln!("       strategy: bnb (default), largest or random");
+    eprintln!("       wallet psbt sign <wallet_file> <psbt_file>");
+    eprintln!("       wallet psbt sign-keystore <keystore_file> <label> <psbt_file>");
+    eprintln!("       wallet psbt combine <psbt_file> <other_psbt_file>...");
+    eprintln!("       wallet psbt finalize <psbt_file>");
+    eprintln!("       wallet psbt extract <psbt_file> <tx_file>");
+    exit(1);
+}
+
+fn prompt_passphrase(prompt: &str) -> String {
+    return rpassword::prompt_password(prompt).expect("Failed to read passphrase");
+}
+
+fn keystore(args: &[String]) {
+    let (command, path) = if let (Some(arg1), Some(arg2)) = (args.first(), args.get(1)) {
+        (arg1, arg2)
+    } else {
+        usage();
+    };
+
+    match (command.as_str(), args.get(2)) {
+        ("new", None) => {
+            let passphrase = new_passphrase();
+            let keystore = Keystore::new(&passphrase).expect("Failed to create keystore");
+            keystore.save_to_file(path).expect("Failed to save keystore");
+        }
+        ("add", Some(label)) => {
+            let mut keystore = Keystore::load_from_file(path).expect("Failed to load keystore");
+            let passphrase = prompt_passphrase("Passphrase: ");
+            let priv_key = PrivateKey::new_key();
+            if let Err(e) = keystore.add_key(&passphrase, label, &priv_key) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+            keystore.save_to_file(path).expect("Failed to save keystore");
+            println!("{}", priv_key.public_key().address(Network::default()));
+        }
+        ("list", None) => {
+            let keystore = Keystore::load_from_file(path).expect("Failed to load keystore");
+            for entry in keystore.entries() {
+                println!("{}\t{}\t{}",
+                        entry.label,
+                        entry.public_key.address(Network::default()),
+                        entry.created_at);
+            }
+        }
+        ("export", Some(label)) => {
+            let keystore = Keystore::load_from_file(path).expect("Failed to load keystore");
+            let passphrase = prompt_passphrase("Passphrase: ");
+            match keystore.export_key(&passphrase, label) {
+                Ok(priv_key) => print!("{}", *priv_key.to_pem().expect("Failed to encode key")),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        ("passwd", None) => {
+            let mut keystore = Keystore::load_from_file(path).expect("Failed to load keystore");
+            let old_passphrase = prompt_passphrase("Current passphrase: ");
+            let new_passphrase = new_passphrase();
+            if let Err(e) = keystore.change_passphrase(&old_passphrase, &new_passphrase) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+            keystore.save_to_file(path).expect("Failed to save keystore");
+        }
+        _ => usage(),
+    }
+}
+
+fn new_passphrase() -> String {
+    let passphrase = prompt_passphrase("New passphrase: ");
+    if passphrase != prompt_passphrase("Repeat passphrase: ") {
+        eprintln!("Passphrases do not match");
+        exit(1);
+    }
+    return passphrase;
+}
+
+const USER_AGENT: &str = concat!("/wallet:", env!("CARGO_PKG_VERSION"), "/");
+
+//connect and handshake with a node on the wallet's network
+fn connect(node: &str, network: Network) -> TcpStream {
+    let mut stream = TcpStream::connect(node).unwrap_or_else(|e| {
+        eprintln!("Failed to connect to {}: {}", node, e);
+        exit(1);
+    });
+    let ours = Version::new(network, 0, Services::NONE, USER_AGENT);
+    if let Err(e) = network::connect_handshake(&mut stream, &ours) {
+        eprintln!("Handshake with {} failed: {}", node, e);
+        exit(1);
+    }
+    return stream;
+}
+
+fn print_balance(owned: &[OwnedOutput]) {
+    let confirmed: u64 = owned.iter()
+                            .filter(|owned| !owned.reserved)
+                            .map(|owned| owned.output.value)
+                            .sum();
+    let reserved: u64 = owned.iter()
+                            .filter(|owned| owned.reserved)
+                            .map(|owned| owned.output.value)
+                            .sum();
+    println!("Balance: {} ({} reserved by pending transactions)", confirmed, reserved);
+}
+
+fn exit_with<T>(e: BtcError) -> T {
+    eprintln!("{}", e);
+    exit(1);
+}
+
+fn psbt(args: &[String]) {
+    let (command, path) = if let (Some(arg1), Some(arg2)) = (args.first(), args.get(1)) {
+        (arg1, arg2)
+    } else {
+        usage();
+    };
+
+    match (command.as_str(), &args[2..]) {
+        ("create", [node, address, amount, fee_rate, psbt_path, strategy @ ..])
+            if strategy.len() <= 1 => {
+            let (to, amount, fee_rate) = parse_payment(address, amount, fee_rate);
+            let strategy = parse_strategy(strategy.first());
+            let mut wallet = Wallet::load_from_file(path).expect("Failed to load wallet");
+            let mut stream = connect(node, wallet.network);
+            let owned = wallet.scan(&mut stream).expect("Failed to scan UTXOs");
+            let fee_rate = resolve_fee_rate(&mut stream, fee_rate);
+            let psbt = wallet.build_psbt(&owned, to, amount, fee_rate, strategy).unwrap_or_else(exit_with);
+            psbt.save_to_file(psbt_path).expect("Failed to save transaction");
+            wallet.save_to_file(path).expect("Failed to save wallet");
+            println!("{}", psbt.hash());
+        }
+        ("sign", [psbt_path]) => {
+            let wallet = Wallet::load_from_file(path).expect("Failed to load wallet");
+            let mut psbt = PartiallySignedTransaction::load_from_file(psbt_path)
+                                .expect("Failed to load transaction");
+            let passphrase = prompt_passphrase("Passphrase: ");
+            let signed = wallet.sign_psbt(&passphrase, &mut psbt).unwrap_or_else(exit_with);
+            psbt.save_to_file(psbt_path).expect("Failed to save transaction");
+            println!("Signed {} of {} inputs", signed, psbt.spent_outputs.len());
+        }
+        ("sign-keystore", [label, psbt_path]) => {
+            let keystore = Keystore::load_from_file(path).expect("Failed to load keystore");
+            let mut psbt = PartiallySignedTransaction::load_from_file(psbt_path)
+                                .expect("Failed to load transaction");
+            let passphrase = prompt_passphrase("Passphrase: ");
+            let priv_key = keystore.export_key(&passphrase, label).unwrap_or_else(exit_with);
+            let signed = psbt.sign(&priv_key).unwrap_or_else(exit_with);
+            psbt.save_to_file(psbt_path).expect("Failed to save transaction");
+            println!("Signed {} of {} inputs", signed, psbt.spent_outputs.len());
+        }
+        ("combine", others) if !others.is_empty() => {
+            let mut psbt = PartiallySignedTransaction::load_from_file(path)
+                                .expect("Failed to load transaction");
+            for other_path in others {
+                let other = PartiallySignedTransaction::load_from_file(other_path)
+                                .expect("Failed to load transaction");
+                psbt.combine(&other).unwrap_or_else(exit_with);
+            }
+            psbt.save_to_file(path).expect("Failed to save transaction");
+        }
+        ("finalize", []) => {
+            let mut psbt = PartiallySignedTransaction::load_from_file(path)
+                                .expect("Failed to load transaction");
+            psbt.finalize().unwrap_or_else(exit_with);
+            psbt.save_to_file(path).expect("Failed to save transaction");
+        }
+        ("extract", [tx_path]) => {
+            let psbt = PartiallySignedTransaction::load_from_file(path)
+                            .expect("Failed to load transaction");
+            let transaction = psbt.extract().unwrap_or_else(exit_with);
+            transaction.save_to_file(tx_path).expect("Failed to save transaction");
+            println!("{}", transaction.hash());
+        }
+        _ => usage(),
+    }
+}
+
+//a fee rate of "auto" is left to the node
+fn parse_payment(address: &str, amount: &str, fee_rate: &str) -> (Address, u64, Option<u64>) {
+    let to: Address = address.parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+    let fee_rate = match fee_rate {
+        "auto" => Ok(None),
+        fee_rate => fee_rate.parse().map(Some),
+    };
+    return match (amount.parse(), fee_rate) {
+        (Ok(amount), Ok(fee_rate)) => (to, amount, fee_rate),
+        _ => {
+            eprintln!("<amount> and <fee_rate> should be integers");
+            exit(1);
+        }
+    };
+}
+
+fn resolve_fee_rate(stream: &mut TcpStream, fee_rate: Option<u64>) -> u64 {
+    return match fee_rate {
+        Some(fee_rate) => fee_rate,
+        None => {
+            let fee_rate = wallet::fetch_fee_estimate(stream, wallet::CONFIRMATION_TARGET)
+                                .expect("Failed to fetch fee estimate");
+            println!("Fee rate: {} per byte", fee_rate);
+            fee_rate
+        }
+    };
+}
+
+fn parse_strategy(strategy: Option<&String>) -> Strategy {
+    return match strategy {
+        Some(strategy) => strategy.parse().unwrap_or_else(exit_with),
+        None => Strategy::default(),
+    };
+}
+
+fn watch(args: &[String]) {
+    let (command, path) = if let (Some(arg1), Some(arg2)) = (args.first(), args.get(1)) {
+        (arg1, arg2)
+    } else {
+        usage();
+    };
+
+    match (command.as_str(), &args[2..]) {
+        ("create", rest) if rest.len() <= 1 => {
+            let account = rest.first().map(|xpub| {
+                xpub.parse::<ExtendedPublicKey>().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    exit(1);
+                })
+            });
+            let network = account.as_ref()
+                            .map(|account| account.network())
+                            .unwrap_or_default();
+            let wallet = Wallet::watch_only(account, network);
+            wallet.save_to_file(path).expect("Failed to save wallet");
+        }
+        ("import", [public_key]) => {
+            let public_key = PublicKey::from_hex(public_key).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(1);
+            });
+            let mut wallet = Wallet::load_from_file(path).expect("Failed to load wallet");
+            println!("{}", public_key.address(wallet.network));
+            wallet.import_public_key(public_key);
+            wallet.save_to_file(path).expect("Failed to save wallet");
+        }
+        _ => usage(),
+    }
+}
+
+fn hd(args: &[String]) {
+    let (command, path) = if let (Some(arg1), Some(arg2)) = (args.first(), args.get(1)) {
+        (arg1, arg2)
+    } else {
+        usage();
+    };
+
+    match (command.as_str(), &args[2..]) {
+        ("create", []) => {
+            let mnemonic = generate_mnemonic();
+            let passphrase = new_passphrase();
+            let wallet = Wallet::from_seed(&mnemonic.to_seed(""), &passphrase,
+                                           Network::default())
+                            .expect("Failed to create wallet");
+            wallet.save_to_file(path).expect("Failed to save wallet");
+            println!("Write down your recovery phrase:");
+            println!("{}", mnemonic);
+        }
+        ("restore", [node]) => {
+            let mut phrase = String::new();
+            eprint!("Recovery phrase: ");
+            stdin().read_line(&mut phrase).expect("Failed to read recovery phrase");
+            let mnemonic = parse_mnemonic(phrase.trim()).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(1);
+            });
+            let passphrase = new_passphrase();
+            let mut wallet = Wallet::from_seed(&mnemonic.to_seed(""), &passphrase,
+                                               Network::default())
+                                .expect("Failed to create wallet");
+            let owned = wallet.scan(&mut connect(node, wallet.network)).expect("Failed to scan UTXOs");
+            wallet.save_to_file(path).expect("Failed to save wallet");
+            print_balance(&owned);
+        }
+        ("receive", []) => {
+            let mut wallet = Wallet::load_from_file(path).expect("Failed to load wallet");
+            let address = wallet.next_receive_address().expect("Failed to derive key");
+            wallet.save_to_file(path).expect("Failed to save wallet");
+            println!("{}", address);
+        }
+        ("balance", [node]) => {
+            let mut wallet = Wallet::load_from_file(path).expect("Failed to load wallet");
+            let owned = wallet.scan(&mut connect(node, wallet.network)).expect("Failed to scan UTXOs");
+            wallet.save_to_file(path).expect("Failed to save wallet");
+            print_balance(&owned);
+        }
+        ("send", [node, address, amount, fee_rate, strategy @ ..]) if strategy.len() <= 1 => {
+            let (to, amount, fee_rate) = parse_payment(address, amount, fee_rate);
+            let strategy = parse_strategy(strategy.first());
+            let mut wallet = Wallet::load_from_file(path).expect("Failed to load wallet");
+            if wallet.is_watch_only() {
+                eprintln!("Wallet is watch-only, use psbt create instead");
+                exit(1);
+            }
+            let mut stream = connect(node, wallet.network);
+            let owned = wallet.scan(&mut stream).expect("Failed to scan UTXOs");
+            let fee_rate = resolve_fee_rate(&mut stream, fee_rate);
+            let mut psbt = wallet.build_psbt(&owned, to, amount, fee_rate, strategy).unwrap_or_else(exit_with);
+            let passphrase = prompt_passphrase("Passphrase: ");
+            wallet.sign_psbt(&passphrase, &mut psbt).unwrap_or_else(exit_with);
+            psbt.finalize().unwrap_or_else(exit_with);
+            let transaction = psbt.extract().unwrap_or_else(exit_with);
+            wallet::submit_transaction(&mut stream, transaction.clone())
+                .expect("Failed to submit transaction");
+            wallet.save_to_file(path).expect("Failed to save wallet");
+            println!("{}", transaction.hash());
+        }
+        ("history", []) => {
+            let wallet = Wallet::load_from_file(path).expect("Failed to load wallet");
+            for entry in &wallet.history {
+                let spent = entry.spent_at
+                                .map(|spent_at| spent_at.to_string())
+                                .unwrap_or_else(|| "unspent".to_owned());
+                println!("{}\t{}\t{}\t{}", entry.received_at, entry.address, entry.value, spent);
+            }
+        }
+        ("xpub", []) => {
+            let wallet = Wallet::load_from_file(path).expect("Failed to load wallet");
+            match &wallet.account {
+                Some(account) => println!("{}", account),
+                None => {
+                    eprintln!("Wallet has no account key");
+                    exit(1);
+                }
+            }
+        }
+        _ => usage(),
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, path) = if let (Some(arg1), Some(arg2)) = (args.first(), args.get(1)) {
+        (arg1, arg2)
+    } else {
+        usage();
+    };
+
+    match command.as_str() {
+        "new" => {
+            let priv_key = PrivateKey::new_key();
+            priv_key.save_to_file(path).expect("Failed to save key");
+            println!("{}", priv_key.public_key().address(Network::default()));
+        }
+        "address" => {
+            let priv_key = PrivateKey::load_from_file(path)
+                                    .expect("Failed to load key");
+            println!("{}", priv_key.public_key().address(Network::default()));
+        }
+        "keystore" => keystore(&args[1..]),
+        "hd" => hd(&args[1..]),
+        "watch" => watch(&args[1..]),
+        "psbt" => psbt(&args[1..]),
+        _ => usage(),
+    }
 }
@@ -0,0 +1,352 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read,
+    Result as IOResult, Write};
+use std::net::TcpStream;
+use uuid::Uuid;
+
+use btclib::crypto::{Address, PrivateKey, PublicKey};
+use btclib::error::{BtcError, Result};
+use btclib::hd::{DerivationPath, ExtendedPrivateKey, ExtendedPublicKey, HARDENED_OFFSET};
+use btclib::keystore::Keystore;
+use btclib::network::{Message, Network};
+use btclib::sha256::Hash;
+use btclib::types::{
+    OutputLock, PartiallySignedTransaction, Transactions, TransactionsOutput, UnsignedTransaction,
+};
+use btclib::util::Saveable;
+
+use crate::coin_selection::{self, Strategy};
+
+//BIP44 style chains below the account key
+pub const RECEIVE_CHAIN: u32 = 0;
+pub const CHANGE_CHAIN: u32 = 1;
+//stop scanning a chain after this many unused keys in a row
+pub const GAP_LIMIT: u32 = 20;
+//blocks to confirm within when the node picks the fee rate
+pub const CONFIRMATION_TARGET: u32 = 6;
+
+//m/44'/0'/0'
+fn account_path() -> DerivationPath {
+    return DerivationPath::default()
+            .child(44 + HARDENED_OFFSET)
+            .child(HARDENED_OFFSET)
+            .child(HARDENED_OFFSET);
+}
+
+//which wallet key an output belongs to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyOrigin {
+    //derived from the account key along chain/index
+    Derived { chain: u32, index: u32 },
+    //position in the imported public keys
+    Imported(usize),
+}
+
+//an unspent output paying one of the wallet's keys
+#[derive(Clone, Debug)]
+pub struct OwnedOutput {
+    pub output: TransactionsOutput,
+    //true if a mempool transaction already spends it
+    pub reserved: bool,
+}
+
+//an output the wallet has seen paid to it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub output_hash: Hash,
+    pub value: u64,
+    pub address: Address,
+    pub received_at: DateTime<Utc>,
+    pub spent_at: Option<DateTime<Utc>>,
+}
+
+/* HD or watch-only wallet. The seed stays encrypted in the
+keystore, public keys are derived from the account xpub without
+the passphrase. Watch-only wallets have no keystore and can only
+build unsigned transactions */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Wallet {
+    pub network: Network,
+    #[serde(default)]
+    pub keystore: Option<Keystore>,
+    #[serde(default)]
+    pub account: Option<ExtendedPublicKey>,
+    #[serde(default)]
+    pub imported: Vec<PublicKey>,
+    pub next_receive: u32,
+    pub next_change: u32,
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+}
+
+impl Wallet {
+
+    pub fn from_seed(seed: &[u8], passphrase: &str, network: Network) -> Result<Self> {
+        let master = ExtendedPrivateKey::from_seed(seed, network)?;
+        let account = master.derive_path(&account_path())?.extended_public_key();
+        let mut keystore = Keystore::new(passphrase)?;
+        keystore.set_seed(passphrase, seed)?;
+        let mut wallet = Self::watch_only(Some(account), network);
+        wallet.keystore = Some(keystore);
+        return Ok(wallet);
+    }
+
+    pub fn watch_only(account: Option<ExtendedPublicKey>, network: Network) -> Self {
+        return Wallet {
+            network,
+            keystore: None,
+            account,
+            imported: Vec::new(),
+            next_receive: 0,
+            next_change: 0,
+            history: Vec::new(),
+        };
+    }
+
+    pub fn is_watch_only(self: &Self) -> bool {
+        return self.keystore.is_none();
+    }
+
+    pub fn import_public_key(self: &mut Self, pubkey: PublicKey) {
+        if !self.imported.contains(&pubkey) {
+            self.imported.push(pubkey);
+        }
+    }
+
+    pub fn public_key(self: &Self, origin: KeyOrigin) -> Result<PublicKey> {
+        return match origin {
+            KeyOrigin::Derived { chain, index } => {
+                let account = self.account.as_ref().ok_or(BtcError::KeyNotFound)?;
+                let key = account.derive_child(chain)?.derive_child(index)?;
+                Ok(key.public_key().clone())
+            }
+            KeyOrigin::Imported(idx) => {
+                self.imported.get(idx).cloned().ok_or(BtcError::KeyNotFound)
+            }
+        };
+    }
+
+    //imported public keys have no private key in the wallet
+    pub fn private_key(self: &Self, passphrase: &str,
+                       origin: KeyOrigin) -> Result<PrivateKey> {
+        let keystore = self.keystore.as_ref().ok_or(BtcError::WatchOnly)?;
+        let KeyOrigin::Derived { chain, index } = origin else {
+            return Err(BtcError::KeyNotFound);
+        };
+        let seed = keystore.seed(passphrase)?;
+        let master = ExtendedPrivateKey::from_seed(&seed, self.network)?;
+        let path = account_path().child(chain).child(index);
+        return Ok(master.derive_path(&path)?.into_private_key());
+    }
+
+    //hand out a fresh receive address
+    pub fn next_receive_address(self: &mut Self) -> Result<Address> {
+        let key = self.public_key(KeyOrigin::Derived {
+            chain: RECEIVE_CHAIN,
+            index: self.next_receive,
+        })?;
+        self.next_receive += 1;
+        return Ok(key.address(self.network));
+    }
+
+    fn scan_key(self: &Self, node: &mut TcpStream, origin: KeyOrigin,
+                owned: &mut Vec<OwnedOutput>) -> IOResult<bool> {
+        let pubkey = self.public_key(origin).map_err(to_io_error)?;
+        let utxos = fetch_utxos(node, &pubkey)?;
+        let used = !utxos.is_empty();
+        owned.extend(utxos.into_iter().map(|(output, reserved)| OwnedOutput {
+            output,
+            reserved,
+        }));
+        return Ok(used);
+    }
+
+    /* ask the node for outputs of every imported key and every
+    account key on both chains until GAP_LIMIT unused keys in a
+    row, moving the next indexes past the last used key. This is
+    also how a restored wallet finds its funds */
+    pub fn scan(self: &mut Self, node: &mut TcpStream) -> IOResult<Vec<OwnedOutput>> {
+        let mut owned = Vec::new();
+        for idx in 0..self.imported.len() {
+            self.scan_key(node, KeyOrigin::Imported(idx), &mut owned)?;
+        }
+        if self.account.is_some() {
+            for chain in [RECEIVE_CHAIN, CHANGE_CHAIN] {
+                let mut index = 0;
+                let mut unused = 0;
+                while unused < GAP_LIMIT {
+                    let origin = KeyOrigin::Derived { chain, index };
+                    if self.scan_key(node, origin, &mut owned)? {
+                        unused = 0;
+                        let next = if chain == RECEIVE_CHAIN {
+                            &mut self.next_receive
+                        } else {
+                            &mut self.next_change
+                        };
+                        *next = (*next).max(index + 1);
+                    } else {
+                        unused += 1;
+                    }
+                    index += 1;
+                }
+            }
+        }
+        self.update_history(&owned);
+        return Ok(owned);
+    }
+
+    //record newly seen outputs and mark vanished ones as spent
+    fn update_history(self: &mut Self, owned: &[OwnedOutput]) {
+        let now = Utc::now();
+        let unspent: HashSet<Hash> = owned.iter()
+                                        .map(|owned| owned.output.hash())
+                                        .collect();
+        for entry in self.history.iter_mut() {
+            if entry.spent_at.is_none() && !unspent.contains(&entry.output_hash) {
+                entry.spent_at = Some(now);
+            }
+        }
+        for owned in owned {
+            let hash = owned.output.hash();
+            if !self.history.iter().any(|entry| entry.output_hash == hash) {
+                self.history.push(HistoryEntry {
+                    output_hash: hash,
+                    value: owned.output.value,
+                    address: owned.output.lock.address(self.network),
+                    received_at: now,
+                    spent_at: None,
+                });
+            }
+        }
+    }
+
+    //fresh change key, or the first input's key without an account
+    fn change_lock(self: &mut Self, selected: &[OwnedOutput]) -> Result<OutputLock> {
+        if self.account.is_some() {
+            let change_key = self.public_key(KeyOrigin::Derived {
+                chain: CHANGE_CHAIN,
+                index: self.next_change,
+            })?;
+            self.next_change += 1;
+            return Ok(OutputLock::PubKeyHash(change_key.pubkey_hash()));
+        }
+        return Ok(selected[0].output.lock.clone());
+    }
+
+    /* pay amount to address from the given outputs at fee_rate
+    per byte, sending the change back to the wallet. Inputs are
+    picked by the coin selection strategy. Needs no private keys */
+    pub fn build_psbt(self: &mut Self, owned: &[OwnedOutput], to: Address,
+                      amount: u64, fee_rate: u64,
+                      strategy: Strategy) -> Result<PartiallySignedTransaction> {
+        let selection = coin_selection::select(owned, amount, fee_rate, strategy)?;
+
+        let mut outputs = vec![TransactionsOutput {
+            value: amount,
+            unique_id: Uuid::new_v4(),
+            lock: OutputLock::from(to),
+        }];
+        if selection.change > 0 {
+            outputs.push(TransactionsOutput {
+                value: selection.change,
+                unique_id: Uuid::new_v4(),
+                lock: self.change_lock(&selection.inputs)?,
+            });
+        }
+        let inputs = selection.inputs.iter().map(|owned| owned.output.hash()).collect();
+        let spent = selection.inputs.into_iter().map(|owned| owned.output).collect();
+        return PartiallySignedTransaction::new(UnsignedTransaction::new(inputs, outputs), spent);
+    }
+
+    //find the wallet key an output is locked to
+    pub fn find_origin(self: &Self, lock: &OutputLock) -> Option<KeyOrigin> {
+        for (idx, pubkey) in self.imported.iter().enumerate() {
+            if lock.is_owned_by(pubkey) {
+                return Some(KeyOrigin::Imported(idx));
+            }
+        }
+        for (chain, next) in [(RECEIVE_CHAIN, self.next_receive), (CHANGE_CHAIN, self.next_change)] {
+            for index in 0..next + GAP_LIMIT {
+                let origin = KeyOrigin::Derived { chain, index };
+                match self.public_key(origin) {
+                    Ok(pubkey) if lock.is_owned_by(&pubkey) => return Some(origin),
+                    Ok(_) => continue,
+                    Err(_) => break,
+                }
+            }
+        }
+        return None;
+    }
+
+    /* add signatures for every input this wallet can spend. Each
+    key is derived once and signs all the inputs locked to it */
+    pub fn sign_psbt(self: &Self, passphrase: &str,
+                     psbt: &mut PartiallySignedTransaction) -> Result<usize> {
+        let mut signed = 0;
+        let origins: HashSet<KeyOrigin> = psbt.spent_outputs.iter()
+                                            .filter_map(|output| self.find_origin(&output.lock))
+                                            .collect();
+        for origin in origins {
+            let key = self.private_key(passphrase, origin)?;
+            signed += psbt.sign(&key)?;
+        }
+        return Ok(signed);
+    }
+}
+
+impl Saveable for Wallet {
+
+    fn load<I: Read>(reader: I) -> IOResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to deserialize Wallet")
+        })
+    }
+
+    fn save<O: Write>(self: &Self, writer: O) -> IOResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IOError::new(IOErrorKind::InvalidData,
+            "Failed to serialize Wallet")
+        })
+    }
+}
+
+fn to_io_error(e: BtcError) -> IOError {
+    return IOError::new(IOErrorKind::InvalidData, e.to_string());
+}
+
+pub fn request(node: &mut TcpStream, message: Message) -> IOResult<Message> {
+    message.send(node).map_err(|e| {
+        IOError::new(IOErrorKind::InvalidData, e.to_string())
+    })?;
+    return Message::recieve(node).map_err(|e| {
+        IOError::new(IOErrorKind::InvalidData, e.to_string())
+    });
+}
+
+pub fn fetch_utxos(node: &mut TcpStream,
+                   pubkey: &PublicKey) -> IOResult<Vec<(TransactionsOutput, bool)>> {
+    return match request(node, Message::FetchUTXOs(pubkey.clone()))? {
+        Message::UTXOs(utxos) => Ok(utxos),
+        _ => Err(IOError::new(IOErrorKind::InvalidData,
+                "Unexpected response to FetchUTXOs")),
+    };
+}
+
+//fee per byte the node expects to confirm within target blocks
+pub fn fetch_fee_estimate(node: &mut TcpStream, target: u32) -> IOResult<u64> {
+    return match request(node, Message::FetchFeeEstimate(target))? {
+        Message::FeeEstimate(fee_rate) => Ok(fee_rate),
+        _ => Err(IOError::new(IOErrorKind::InvalidData,
+                "Unexpected response to FetchFeeEstimate")),
+    };
+}
+
+pub fn submit_transaction(node: &mut TcpStream, transaction: Transactions) -> IOResult<()> {
+    Message::SubmitTransaction(transaction).send(node).map_err(|e| {
+        IOError::new(IOErrorKind::InvalidData, e.to_string())
+    })?;
+    return Ok(());
+}